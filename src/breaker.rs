@@ -0,0 +1,258 @@
+use crate::config::Host;
+use crate::envelope::UpstreamResponse;
+use anyhow::Error as AError;
+use gotham::hyper::StatusCode;
+use serde_json::{json, Map, Value};
+
+use log::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * When the circuit breaker of a relay opens, and for how long
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BreakerConfig {
+    /// Number of recent requests the failure ratio is computed on
+    pub window: usize,
+    /// The breaker opens when this ratio of the recent requests failed
+    pub failure_ratio: f64,
+    /// Requests slower than this count as failures
+    pub slow_threshold: Duration,
+    /// How long the breaker stays open before a request is let through to probe the relay
+    pub open_duration: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            window: 20,
+            failure_ratio: 0.5,
+            slow_threshold: Duration::from_secs(5),
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Display for BreakerConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "open when {}% of {} requests fail or take more than {:?}, for {:?}",
+            self.failure_ratio * 100.0,
+            self.window,
+            self.slow_threshold,
+            self.open_duration
+        ))
+    }
+}
+
+/**
+ * The error returned instead of forwarding an envelope while the breaker of its relay is open
+ */
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub host: Host,
+    pub retry_after: Duration,
+}
+
+impl CircuitOpen {
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+impl Display for CircuitOpen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "The circuit breaker of {} is open, retry in {:?}",
+            self.host, self.retry_after
+        ))
+    }
+}
+
+impl Error for CircuitOpen {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Requests go through, and their outcome is recorded
+    Closed,
+    /// Requests fail fast until this instant
+    Open(Instant),
+    /// A single request, started at this instant, probes the relay
+    HalfOpen(Instant),
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: State,
+    /// Outcomes of the recent requests, true for failures
+    outcomes: VecDeque<bool>,
+    trips: u64,
+}
+
+impl Breaker {
+    fn failures(&self) -> usize {
+        self.outcomes.iter().filter(|failed| **failed).count()
+    }
+}
+
+/**
+ * A circuit breaker per relay. Once too many of the recent requests to a relay failed or were too
+ * slow, the breaker opens and requests fail fast. After a while a single request is let through :
+ * the breaker closes again if it succeeds, and stays open otherwise.
+ */
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    config: BreakerConfig,
+    breakers: Mutex<HashMap<Host, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: BreakerConfig) -> CircuitBreakers {
+        CircuitBreakers {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Check that a request can be sent to this relay. Returns how long the breaker stays open
+     * when it can not.
+     */
+    pub fn allow(&self, host: &Host) -> Result<(), CircuitOpen> {
+        let now = Instant::now();
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = match breakers.get_mut(host) {
+            Some(breaker) => breaker,
+            None => return Ok(()),
+        };
+        let open = |retry_after| {
+            Err(CircuitOpen {
+                host: host.clone(),
+                retry_after,
+            })
+        };
+        match breaker.state {
+            State::Closed => Ok(()),
+            State::Open(until) if now < until => open(until - now),
+            // A probe that never recorded its outcome (its client went away) is replaced
+            State::HalfOpen(started) if now < started + self.config.open_duration => {
+                open(started + self.config.open_duration - now)
+            }
+            State::Open(_) | State::HalfOpen(_) => {
+                info!(
+                    "Circuit breaker of {} is half-open, probing the relay",
+                    host
+                );
+                breaker.state = State::HalfOpen(now);
+                Ok(())
+            }
+        }
+    }
+
+    /**
+     * Returns true while requests to this relay fail fast, without changing the state of its
+     * breaker
+     */
+    pub fn is_open(&self, host: &Host) -> bool {
+        let now = Instant::now();
+        let breakers = self.breakers.lock().unwrap();
+        match breakers.get(host).map(|breaker| breaker.state) {
+            Some(State::Open(until)) => now < until,
+            Some(State::HalfOpen(started)) => now < started + self.config.open_duration,
+            _ => false,
+        }
+    }
+
+    /**
+     * Record the outcome of a request to this relay, which took `latency`. Failures are errors,
+     * 5xx answers and answers slower than the configured threshold.
+     */
+    pub fn record(
+        &self,
+        host: &Host,
+        result: &Result<UpstreamResponse, AError>,
+        latency: Duration,
+    ) {
+        let failed = match result {
+            Ok(response) => response.status.is_server_error(),
+            Err(_) => true,
+        } || latency > self.config.slow_threshold;
+        let now = Instant::now();
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.clone()).or_insert_with(|| Breaker {
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+            trips: 0,
+        });
+        match breaker.state {
+            State::HalfOpen(_) if failed => {
+                warn!(
+                    "Circuit breaker of {} is open again, the probe failed",
+                    host
+                );
+                breaker.state = State::Open(now + self.config.open_duration);
+                breaker.trips += 1;
+            }
+            State::HalfOpen(_) => {
+                info!(
+                    "Circuit breaker of {} is closed, the relay is healthy again",
+                    host
+                );
+                breaker.state = State::Closed;
+                breaker.outcomes.clear();
+            }
+            // Requests started before the breaker opened
+            State::Open(_) => {}
+            State::Closed => {
+                breaker.outcomes.push_back(failed);
+                if breaker.outcomes.len() > self.config.window {
+                    breaker.outcomes.pop_front();
+                }
+                let window = self.config.window.max(1);
+                let failures = breaker.failures();
+                if breaker.outcomes.len() >= window
+                    && failures as f64 >= window as f64 * self.config.failure_ratio
+                {
+                    warn!(
+                        "Circuit breaker of {} is open, {} of the last {} requests failed",
+                        host, failures, window
+                    );
+                    breaker.state = State::Open(now + self.config.open_duration);
+                    breaker.outcomes.clear();
+                    breaker.trips += 1;
+                }
+            }
+        }
+    }
+
+    /**
+     * The state of the breaker of every relay, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        let now = Instant::now();
+        let breakers = self.breakers.lock().unwrap();
+        let mut stats = Map::new();
+        for (host, breaker) in breakers.iter() {
+            let state = match breaker.state {
+                State::Closed => "closed",
+                State::Open(until) if now < until => "open",
+                State::Open(_) | State::HalfOpen(_) => "half-open",
+            };
+            stats.insert(
+                host.to_string(),
+                json!({
+                    "state": state,
+                    "requests": breaker.outcomes.len(),
+                    "failures": breaker.failures(),
+                    "trips": breaker.trips,
+                }),
+            );
+        }
+        Value::Object(stats)
+    }
+}
@@ -0,0 +1,212 @@
+use gotham::hyper::Uri;
+use isahc::config::{Configurable, ResolveMap, VersionNegotiation};
+use isahc::HttpClient;
+
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/**
+ * The schemes of the supported proxies : HTTP proxies, used with CONNECT for https relays, and
+ * SOCKS5 proxies, which resolve the relay host themselves with `socks5h`
+ */
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/**
+ * The HTTP version used to talk to the sentry relays
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the relay announces it over TLS, HTTP/1.1 otherwise
+    Auto,
+    /// HTTP/1.1 only
+    Http1,
+    /// HTTP/2 only, including over plain text connections
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(HttpVersion::Auto),
+            "http1" | "http1.1" => Ok(HttpVersion::Http1),
+            "http2" => Ok(HttpVersion::Http2),
+            other => Err(format!(
+                "{} is not a valid http version, use auto, http1 or http2",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            HttpVersion::Auto => "auto",
+            HttpVersion::Http1 => "http1",
+            HttpVersion::Http2 => "http2",
+        })
+    }
+}
+
+/**
+ * A static address for a relay host and port, used instead of resolving the host
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsOverride {
+    pub host: String,
+    pub port: u16,
+    pub addr: IpAddr,
+}
+
+impl FromStr for DnsOverride {
+    type Err = String;
+
+    /**
+     * Parse an override written as `<host>:<port>=<ip>`. The port is required : the override
+     * only applies to connections to this port, which would silently resolve the host otherwise.
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a valid dns override, use <host>:<port>=<ip>", s);
+        let (host, addr) = s.trim().rsplit_once('=').ok_or_else(invalid)?;
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| invalid())?;
+        let (host, port) = host.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let port = u16::from_str(port).map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(DnsOverride {
+            host: host.to_string(),
+            port,
+            addr,
+        })
+    }
+}
+
+impl Display for DnsOverride {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{} => {}", self.host, self.port, self.addr))
+    }
+}
+
+/**
+ * Parse the url of an upstream proxy, checking that it is an HTTP or SOCKS5 proxy
+ */
+pub fn parse_proxy(url: &str) -> Result<Uri, String> {
+    let proxy = Uri::from_str(url.trim())
+        .map_err(|e| format!("{} is not a valid proxy url : {}", url, e))?;
+    match proxy.scheme_str() {
+        Some(scheme) if PROXY_SCHEMES.contains(&scheme) && proxy.host().is_some() => Ok(proxy),
+        _ => Err(format!(
+            "{} is not a valid proxy url, use http://, https://, socks5:// or socks5h://",
+            url
+        )),
+    }
+}
+
+/**
+ * The timeouts, connection pool, HTTP version, proxy and static addresses of the client
+ * forwarding envelopes
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    /// Maximum time to establish a connection to a relay
+    pub connect_timeout: Duration,
+    /// A request is aborted when no data was sent or received for this long
+    pub read_timeout: Duration,
+    /// Maximum time of a whole request, from connecting to reading the answer
+    pub timeout: Duration,
+    /// Maximum number of connections to each relay, 0 for no limit
+    pub max_connections_per_host: usize,
+    pub version: HttpVersion,
+    /// Proxy that every request to the relays goes through
+    pub proxy: Option<Uri>,
+    /// Hosts that are reached without the proxy
+    pub no_proxy: Vec<String>,
+    pub dns_overrides: Vec<DnsOverride>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            timeout: Duration::from_secs(60),
+            max_connections_per_host: 0,
+            version: HttpVersion::Auto,
+            proxy: None,
+            no_proxy: vec![],
+            dns_overrides: vec![],
+        }
+    }
+}
+
+impl Display for ClientConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "connect timeout of {:?}, read timeout of {:?}, timeout of {:?}, {} connections per host, {} http version",
+            self.connect_timeout,
+            self.read_timeout,
+            self.timeout,
+            match self.max_connections_per_host {
+                0 => "unlimited".to_string(),
+                max => max.to_string(),
+            },
+            self.version
+        ))?;
+        if let Some(proxy) = &self.proxy {
+            // The proxy url may contain credentials, which are not shown
+            f.write_fmt(format_args!(
+                ", proxy {}://{}",
+                proxy.scheme_str().unwrap_or_default(),
+                proxy.authority().map_or("", |authority| {
+                    authority.as_str().rsplit('@').next().unwrap_or_default()
+                })
+            ))?;
+            if !self.no_proxy.is_empty() {
+                f.write_fmt(format_args!(" except for {}", self.no_proxy.join(", ")))?;
+            }
+        }
+        for dns_override in &self.dns_overrides {
+            f.write_fmt(format_args!(", {}", dns_override))?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    /**
+     * Build the client shared by every request forwarded to the relays, which keeps their
+     * connections alive between requests
+     */
+    pub fn build(&self) -> Result<HttpClient, isahc::Error> {
+        let mut builder = HttpClient::builder()
+            .connect_timeout(self.connect_timeout)
+            .low_speed_timeout(1, self.read_timeout)
+            .timeout(self.timeout)
+            .version_negotiation(match self.version {
+                HttpVersion::Auto => VersionNegotiation::latest_compatible(),
+                HttpVersion::Http1 => VersionNegotiation::http11(),
+                HttpVersion::Http2 => VersionNegotiation::http2(),
+            });
+        if self.max_connections_per_host > 0 {
+            builder = builder.max_connections_per_host(self.max_connections_per_host);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder
+                .proxy(proxy.clone())
+                .proxy_blacklist(self.no_proxy.clone());
+        }
+        if !self.dns_overrides.is_empty() {
+            let mut resolve = ResolveMap::new();
+            for dns_override in &self.dns_overrides {
+                resolve = resolve.add(&dns_override.host, dns_override.port, dns_override.addr);
+            }
+            builder = builder.dns_resolve(resolve);
+        }
+        builder.build()
+    }
+}
@@ -0,0 +1,248 @@
+use bytes::Bytes;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use gotham::handler::IntoResponse;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::state::State;
+use mime::Mime;
+
+use log::*;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/**
+ * Bodies bigger than this are compressed, or decompressed when they may decode to more than this,
+ * on the blocking thread pool so that they do not hold up the workers of the runtime
+ */
+const BLOCKING_COMPRESSION_SIZE: usize = 64 * 1024;
+
+/**
+ * A content encoding that this tunnel knows how to decode
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl FromStr for ContentEncoding {
+    type Err = DecodingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "identity" | "" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            "br" => Ok(ContentEncoding::Brotli),
+            "zstd" => Ok(ContentEncoding::Zstd),
+            other => Err(DecodingError::UnsupportedEncoding(other.to_string())),
+        }
+    }
+}
+
+/**
+ * An error raised while decoding a compressed request body
+ */
+#[derive(Debug)]
+pub enum DecodingError {
+    UnsupportedEncoding(String),
+    DecompressedBodyTooBig,
+    CompressionRatioTooHigh,
+    InvalidCompressedBody(std::io::Error),
+}
+
+impl DecodingError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            DecodingError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            DecodingError::DecompressedBodyTooBig | DecodingError::CompressionRatioTooHigh => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            DecodingError::InvalidCompressedBody(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for DecodingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodingError::UnsupportedEncoding(encoding) => {
+                f.write_fmt(format_args!("Unsupported content encoding : {}", encoding))
+            }
+            DecodingError::DecompressedBodyTooBig => {
+                f.write_str("Decompressed body is bigger than the allowed size.")
+            }
+            DecodingError::CompressionRatioTooHigh => {
+                f.write_str("Compression ratio of the body is higher than the allowed ratio.")
+            }
+            DecodingError::InvalidCompressedBody(e) => {
+                f.write_fmt(format_args!("Failed to decompress body : {}", e))
+            }
+        }
+    }
+}
+
+impl Error for DecodingError {}
+
+impl IntoResponse for DecodingError {
+    fn into_response(self, state: &State) -> Response<Body> {
+        warn!("{}", self);
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        create_response(state, self.status_code(), mime, format!("{}", self))
+    }
+}
+
+/**
+ * Decode a body according to the value of its `Content-Encoding` header.
+ *
+ * Encodings are listed in the order they were applied, so they are removed in reverse order. The
+ * decoded body can not be bigger than `max_size`, nor bigger than `max_ratio` times the size of
+ * the encoded body, to protect against decompression bombs.
+ */
+pub fn decode_body(
+    body: Bytes,
+    content_encoding: &str,
+    max_size: u64,
+    max_ratio: u64,
+) -> Result<Bytes, DecodingError> {
+    let encodings = content_encoding
+        .split(',')
+        .map(ContentEncoding::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    let allowed_size = max_size.min(max_ratio.saturating_mul(body.len() as u64));
+
+    let mut decoded = body;
+    for encoding in encodings.into_iter().rev() {
+        decoded = decode_layer(&decoded, encoding, allowed_size)?.unwrap_or(decoded);
+        if decoded.len() as u64 > allowed_size {
+            return Err(if allowed_size == max_size {
+                DecodingError::DecompressedBodyTooBig
+            } else {
+                DecodingError::CompressionRatioTooHigh
+            });
+        }
+    }
+    Ok(decoded)
+}
+
+/**
+ * Decode a body from async code like `decode_body`, on the blocking thread pool when it may
+ * decode to a big body
+ */
+pub async fn decode_body_async(
+    body: Bytes,
+    content_encoding: String,
+    max_size: u64,
+    max_ratio: u64,
+) -> Result<Bytes, DecodingError> {
+    let allowed_size = max_size.min(max_ratio.saturating_mul(body.len() as u64));
+    if allowed_size <= BLOCKING_COMPRESSION_SIZE as u64 {
+        return decode_body(body, &content_encoding, max_size, max_ratio);
+    }
+    tokio::task::spawn_blocking(move || decode_body(body, &content_encoding, max_size, max_ratio))
+        .await
+        .map_err(|e| DecodingError::InvalidCompressedBody(std::io::Error::other(e)))?
+}
+
+/**
+ * Decode a single encoding layer, reading at most one byte past `allowed_size` so that the caller
+ * can detect an oversized body. Returns None for the identity encoding.
+ */
+fn decode_layer(
+    body: &[u8],
+    encoding: ContentEncoding,
+    allowed_size: u64,
+) -> Result<Option<Bytes>, DecodingError> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        ContentEncoding::Identity => return Ok(None),
+        ContentEncoding::Gzip => Box::new(MultiGzDecoder::new(body)),
+        ContentEncoding::Deflate => Box::new(ZlibDecoder::new(body)),
+        ContentEncoding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+        ContentEncoding::Zstd => Box::new(
+            zstd::stream::read::Decoder::new(body).map_err(DecodingError::InvalidCompressedBody)?,
+        ),
+    };
+    let mut decoded = vec![];
+    reader
+        .take(allowed_size.saturating_add(1))
+        .read_to_end(&mut decoded)
+        .map_err(DecodingError::InvalidCompressedBody)?;
+    Ok(Some(Bytes::from(decoded)))
+}
+
+/**
+ * The compression applied to envelopes forwarded to an upstream sentry relay
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UpstreamCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for UpstreamCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" | "identity" => Ok(UpstreamCompression::None),
+            "gzip" => Ok(UpstreamCompression::Gzip),
+            "zstd" => Ok(UpstreamCompression::Zstd),
+            other => Err(format!(
+                "{} is not a valid upstream compression, use one of none, gzip or zstd",
+                other
+            )),
+        }
+    }
+}
+
+impl UpstreamCompression {
+    /**
+     * The value of the `Content-Encoding` header matching this compression
+     */
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            UpstreamCompression::None => None,
+            UpstreamCompression::Gzip => Some("gzip"),
+            UpstreamCompression::Zstd => Some("zstd"),
+        }
+    }
+
+    /**
+     * Compress a body. Returns None when this compression is `None`.
+     */
+    pub fn compress(&self, body: &[u8]) -> std::io::Result<Option<Bytes>> {
+        let compressed = match self {
+            UpstreamCompression::None => return Ok(None),
+            UpstreamCompression::Gzip => {
+                let mut encoder =
+                    GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            UpstreamCompression::Zstd => zstd::stream::encode_all(body, 0)?,
+        };
+        Ok(Some(Bytes::from(compressed)))
+    }
+
+    /**
+     * Compress a body from async code, on the blocking thread pool when it is big. Returns None
+     * when this compression is `None`.
+     */
+    pub async fn compress_async(self, body: Bytes) -> std::io::Result<Option<Bytes>> {
+        if self == UpstreamCompression::None || body.len() <= BLOCKING_COMPRESSION_SIZE {
+            return self.compress(&body);
+        }
+        tokio::task::spawn_blocking(move || self.compress(&body))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+}
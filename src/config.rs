@@ -1,15 +1,195 @@
+use crate::breaker::BreakerConfig;
+use crate::client::{parse_proxy, ClientConfig, DnsOverride, HttpVersion};
+use crate::compression::UpstreamCompression;
+use crate::pool::Balancing;
+use crate::queue::{QueueConfig, QueueFullPolicy};
+use crate::retry::RetryPolicy;
+use crate::server::MAX_CONTENT_SIZE;
+use crate::shedding::Priority;
+use crate::spool::{EvictionPolicy, SpoolConfig};
+use crate::throttle::BucketConfig;
 use envmnt::ListOptions;
+use sentry_types::{Dsn, ParseDsnError};
 
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
-use log::error;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Host(pub String);
+/**
+ * The origin of a sentry relay : its scheme, hostname and port
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Host {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Host {
+    /**
+     * The origin that envelopes for this dsn are sent to
+     */
+    pub fn from_dsn(dsn: &Dsn) -> Host {
+        Host {
+            scheme: dsn.scheme().to_string(),
+            host: dsn.host().to_string(),
+            port: dsn.port(),
+        }
+    }
+
+    /**
+     * Build a dsn pointing to this host
+     */
+    pub fn dsn(&self, public_key: &str, project_id: u64) -> Result<Dsn, ParseDsnError> {
+        Dsn::from_str(&format!(
+            "{}://{}@{}:{}/{}",
+            self.scheme, public_key, self.host, self.port, project_id
+        ))
+    }
+
+    /**
+     * Returns true if the other host matches this one. A `*` label of this hostname matches
+     * exactly one label of the other hostname, so `*.ingest.sentry.io` matches
+     * `o123.ingest.sentry.io` but not `o123.ingest.de.sentry.io`.
+     */
+    pub fn matches(&self, other: &Host) -> bool {
+        if self.scheme != other.scheme || self.port != other.port {
+            return false;
+        }
+        let labels = self.host.split('.').collect::<Vec<_>>();
+        let other_labels = other.host.split('.').collect::<Vec<_>>();
+        labels.len() == other_labels.len()
+            && labels
+                .iter()
+                .zip(other_labels)
+                .all(|(label, other)| *label == "*" || label.eq_ignore_ascii_case(other))
+    }
+
+    /**
+     * Returns true when the hostname has a `*` label, which matches several hosts
+     */
+    pub fn is_wildcard(&self) -> bool {
+        self.host.split('.').any(|label| label == "*")
+    }
+
+    /**
+     * Returns the organization id of a sentry SaaS ingest hostname, such as 123456 for
+     * `o123456.ingest.sentry.io`
+     */
+    pub fn org_id(&self) -> Option<u64> {
+        self.host
+            .split('.')
+            .next()
+            .and_then(|label| label.strip_prefix('o'))
+            .and_then(|id| id.parse().ok())
+    }
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim()).map_err(|e| format!("{} is not a valid url : {}", s, e))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("{} is not an http or https url", url));
+        }
+        match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => Ok(Host {
+                scheme: url.scheme().to_string(),
+                host: host.to_string(),
+                port,
+            }),
+            _ => Err(format!("{} is not an URL to a remote host", url)),
+        }
+    }
+}
 
 impl Display for Host {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        self.0.fmt(f)
+        let default_port = if self.scheme == "https" { 443 } else { 80 };
+        if self.port == default_port {
+            f.write_fmt(format_args!("{}://{}", self.scheme, self.host))
+        } else {
+            f.write_fmt(format_args!(
+                "{}://{}:{}",
+                self.scheme, self.host, self.port
+            ))
+        }
+    }
+}
+
+/**
+ * The upstream that envelopes of a project are sent to
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    /// The sentry instance that the dsn of the envelopes must point to
+    pub upstream: Host,
+    /// Where envelopes are actually sent, when it is not the upstream itself
+    pub target: Option<Host>,
+    /// The dsn used for envelopes of this route that do not have one, instead of the default dsn
+    /// of the config
+    pub default_dsn: Option<Dsn>,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /**
+     * Parse a route written as `<upstream url>` or `<upstream url>><target url>`, optionally
+     * followed by `|<default dsn>`. An upstream with a `*` label can not be sent to, so it needs
+     * a target, and the default dsn must point to the upstream.
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, default_dsn) = match s.split_once('|') {
+            Some((route, dsn)) => (
+                route,
+                Some(
+                    Dsn::from_str(dsn.trim())
+                        .map_err(|e| format!("{} is not a valid dsn : {}", dsn, e))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let (upstream, target) = match s.split_once('>') {
+            Some((upstream, target)) => (Host::from_str(upstream)?, Some(Host::from_str(target)?)),
+            None => (Host::from_str(s)?, None),
+        };
+        if upstream.is_wildcard() && target.is_none() {
+            return Err(format!(
+                "The route to {} needs a target, use <upstream url>><target url>",
+                upstream
+            ));
+        }
+        if let Some(dsn) = &default_dsn {
+            if !upstream.matches(&Host::from_dsn(dsn)) {
+                return Err(format!(
+                    "The default dsn {} of the route to {} must point to it",
+                    dsn, upstream
+                ));
+            }
+        }
+        Ok(Route {
+            upstream,
+            target,
+            default_dsn,
+        })
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.target {
+            Some(target) => f.write_fmt(format_args!("{} (through {})", self.upstream, target))?,
+            None => self.upstream.fmt(f)?,
+        }
+        if let Some(default_dsn) = &self.default_dsn {
+            f.write_fmt(format_args!(", default dsn {}", default_dsn))?;
+        }
+        Ok(())
     }
 }
 
@@ -20,42 +200,349 @@ pub struct Config {
     pub port: u16,
     pub tunnel_path: String,
     pub ip: String,
+    pub default_compression: UpstreamCompression,
+    pub host_compression: HashMap<Host, UpstreamCompression>,
+    pub compression_threshold: usize,
+    pub stream_bodies: bool,
+    pub max_content_size: u64,
+    pub default_dsn: Option<Dsn>,
+    pub routes: HashMap<String, Route>,
+    pub allowed_dsns: Vec<Dsn>,
+    pub org_ids: Vec<u64>,
+    pub dsn_rewrites: HashMap<(String, u64), Dsn>,
+    pub ip_rate_limit: Option<BucketConfig>,
+    pub project_rate_limit: Option<BucketConfig>,
+    pub origin_rate_limit: Option<BucketConfig>,
+    pub retry: RetryPolicy,
+    pub spool: Option<SpoolConfig>,
+    pub queue: Option<QueueConfig>,
+    pub max_in_flight: Option<usize>,
+    pub category_priorities: HashMap<String, Priority>,
+    pub breaker: Option<BreakerConfig>,
+    pub relays: Vec<Host>,
+    pub balancing: Balancing,
+    pub client: ClientConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            remote_hosts: vec![],
+            project_ids: vec![],
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            ip: "127.0.0.1".to_string(),
+            default_compression: UpstreamCompression::None,
+            host_compression: HashMap::new(),
+            compression_threshold: 1024,
+            stream_bodies: false,
+            max_content_size: MAX_CONTENT_SIZE,
+            default_dsn: None,
+            routes: HashMap::new(),
+            allowed_dsns: vec![],
+            org_ids: vec![],
+            dsn_rewrites: HashMap::new(),
+            ip_rate_limit: None,
+            project_rate_limit: None,
+            origin_rate_limit: None,
+            retry: RetryPolicy::default(),
+            spool: None,
+            queue: None,
+            max_in_flight: None,
+            category_priorities: HashMap::new(),
+            breaker: None,
+            relays: vec![],
+            balancing: Balancing::RoundRobin,
+            client: ClientConfig::default(),
+        }
+    }
 }
 
 impl Display for Config {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "Listening on {}:{}{}\nForwarding requests to : {:?}\nValid project ids : {:?}",
-            self.ip, self.port, self.tunnel_path, self.remote_hosts, self.project_ids
-        ))
+            "Listening on {}:{}{}\nForwarding requests to : {}\nValid project ids : {:?}",
+            self.ip,
+            self.port,
+            self.tunnel_path,
+            self.remote_hosts
+                .iter()
+                .map(Host::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.project_ids
+        ))?;
+        for (key, route) in &self.routes {
+            f.write_fmt(format_args!("\nRoute : {} => {}", key, route))?;
+        }
+        if !self.org_ids.is_empty() {
+            f.write_fmt(format_args!(
+                "\nValid organization ids : {:?}",
+                self.org_ids
+            ))?;
+        }
+        for dsn in &self.allowed_dsns {
+            f.write_fmt(format_args!("\nAllowed dsn : {}", dsn))?;
+        }
+        for (name, rate_limit) in [
+            ("client ip", &self.ip_rate_limit),
+            ("project", &self.project_rate_limit),
+            ("origin", &self.origin_rate_limit),
+        ] {
+            if let Some(rate_limit) = rate_limit {
+                f.write_fmt(format_args!("\nRate limit per {} : {}", name, rate_limit))?;
+            }
+        }
+        if self.retry.max_attempts > 1 {
+            f.write_fmt(format_args!("\nRetries : {}", self.retry))?;
+        }
+        if let Some(spool) = &self.spool {
+            f.write_fmt(format_args!("\nSpool : {}", spool))?;
+        }
+        if let Some(queue) = &self.queue {
+            f.write_fmt(format_args!("\nAsynchronous forwarding queue : {}", queue))?;
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            f.write_fmt(format_args!(
+                "\nMaximum envelopes in flight : {}",
+                max_in_flight
+            ))?;
+        }
+        for (category, priority) in &self.category_priorities {
+            f.write_fmt(format_args!("\nPriority : {} => {}", category, priority))?;
+        }
+        if let Some(breaker) = &self.breaker {
+            f.write_fmt(format_args!("\nCircuit breakers : {}", breaker))?;
+        }
+        f.write_fmt(format_args!("\nHttp client : {}", self.client))?;
+        if !self.relays.is_empty() {
+            f.write_fmt(format_args!(
+                "\nRelay pool ({}) : {}",
+                self.balancing,
+                self.relays
+                    .iter()
+                    .map(Host::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))?;
+        }
+        for ((public_key, project_id), dsn) in &self.dsn_rewrites {
+            f.write_fmt(format_args!(
+                "\nRewrite : {}/{} => {}",
+                public_key, project_id, dsn
+            ))?;
+        }
+        Ok(())
     }
 }
 
 impl Config {
     /**
      * Create a new config from env variables :
-     * - TUNNEL_REMOTE_HOST : Comma separated list of valid sentry relays
+     * - TUNNEL_REMOTE_HOST : Comma separated list of valid sentry relays. Scheme, hostname and
+     *   port of a dsn must all match one of them. A `*` label of a hostname matches any single
+     *   label, as in `*.ingest.sentry.io`. Optional when TUNNEL_ROUTES or
+     *   TUNNEL_ALLOWED_DSNS is set.
      * - TUNNEL_PROJECT_IDS : Comma separated list of valid project ids that can be forwarded to
-     * sentry
+     *   sentry. Optional when TUNNEL_ROUTES or TUNNEL_ALLOWED_DSNS is set.
+     * - TUNNEL_ALLOWED_DSNS : Comma separated list of complete dsns. When set, the public key,
+     *   project id and host of a dsn must all match one of them. Optional.
+     * - TUNNEL_ORG_IDS : Comma separated list of sentry SaaS organization ids. When set, the
+     *   hostname of a dsn must start with one of them, as in `o123456.ingest.sentry.io`.
+     *   Optional.
+     * - TUNNEL_DSN_REWRITES : Comma separated list of `<public key>/<project id>=<dsn>` entries.
+     *   Envelopes accepted for that public key and project id are forwarded with the new dsn,
+     *   which replaces the one of the envelope header. Optional.
+     * - TUNNEL_ROUTES : Comma separated routing table, where each entry is
+     *   `<project id or public key>=<upstream url>`, or `<key>=<upstream url>><target url>` to
+     *   send the envelopes to another address than the upstream. An entry may end with
+     *   `|<dsn>`, used instead of TUNNEL_DEFAULT_DSN for the envelopes of this route that do not
+     *   have a dsn. When set, a dsn must have a route and point to its upstream, and
+     *   TUNNEL_REMOTE_HOST and TUNNEL_PROJECT_IDS are not used anymore. Optional.
      * - TUNNEL_LISTEN_PORT : Optionnal listen port, 7878 by default
      * - TUNNEL_PATH : Url path where this tunnel is waiting for sentry requests. By default
      * - TUNNEL_IP : Listen interface. Optional, 127.0.0.1 by default.
+     * - TUNNEL_UPSTREAM_COMPRESSION : Optional comma separated list of compressions (none, gzip or
+     *   zstd) used when forwarding envelopes. An entry is either a compression applied to every
+     *   relay, or `<relay url>=<compression>` for a single relay. None by default.
+     * - TUNNEL_UPSTREAM_COMPRESSION_THRESHOLD : Bodies smaller than this number of bytes are
+     *   forwarded uncompressed. Optional, 1024 by default.
+     * - TUNNEL_STREAM_BODIES : If true, uncompressed request bodies are streamed to the relay once
+     *   their envelope header has been checked, instead of being read in memory first. Optional,
+     *   false by default.
+     * - TUNNEL_MAX_CONTENT_SIZE : Maximum size of a request body, in bytes. Optional, 10 MB by
+     *   default.
+     * - TUNNEL_DEFAULT_DSN : Dsn used for envelopes sent to the tunnel without a dsn in their
+     *   header. Its public key is replaced by the one from the `sentry_key` query parameter or
+     *   the `X-Sentry-Auth` header when the request has one. Optional.
+     * - TUNNEL_RATE_LIMIT_IP, TUNNEL_RATE_LIMIT_PROJECT, TUNNEL_RATE_LIMIT_ORIGIN : Token buckets
+     *   limiting the requests of each client ip, project id and `Origin` header, written as
+     *   `<requests per second>` or `<requests per second>:<burst>`. Optional, unlimited by default.
+     * - TUNNEL_RETRY_ATTEMPTS : Maximum number of attempts to forward an envelope when the relay
+     *   can not be reached or answers 502, 503 or 504. Optional, 1 (no retries) by default.
+     * - TUNNEL_RETRY_BACKOFF_MS, TUNNEL_RETRY_MAX_BACKOFF_MS : Initial and maximum delay between
+     *   two attempts, in milliseconds. Optional, 100 and 5000 by default.
+     * - TUNNEL_RETRY_DEADLINE_MS : No attempt is started after this many milliseconds. Optional,
+     *   10000 by default.
+     * - TUNNEL_RETRY_TIMEOUTS : If true, requests that timed out are retried too, and may deliver
+     *   an envelope twice. Optional, false by default.
+     * - TUNNEL_SPOOL_DIR : Directory where envelopes are spooled while the relay can not be
+     *   reached. Optional, envelopes are not spooled by default.
+     * - TUNNEL_SPOOL_MAX_SIZE : Maximum size of the spool, in bytes. Optional, 100 MB by default.
+     * - TUNNEL_SPOOL_MAX_AGE_SECS : Spooled envelopes older than this are dropped. Optional, one
+     *   day by default.
+     * - TUNNEL_SPOOL_SEGMENT_SIZE : Size of the spool segment files, in bytes. Optional, 1 MB by
+     *   default.
+     * - TUNNEL_SPOOL_EVICTION : `oldest` to drop the oldest envelopes when the spool is full, or
+     *   `newest` to refuse new ones. Optional, `oldest` by default.
+     * - TUNNEL_ASYNC_QUEUE_SIZE : When set, clients are answered as soon as their envelope is
+     *   validated, and envelopes are forwarded from an in-memory queue of this size. Optional.
+     * - TUNNEL_ASYNC_WORKERS : Number of tasks forwarding queued envelopes. Optional, 4 by
+     *   default.
+     * - TUNNEL_ASYNC_QUEUE_FULL : `reject` to answer 503 when the queue is full, or `drop` to drop
+     *   the envelope. Optional, `reject` by default.
+     * - TUNNEL_MAX_IN_FLIGHT : Maximum number of envelopes handled at the same time. Low priority
+     *   envelopes are refused with a 429 once half of it is used, normal ones once 80% of it is
+     *   used. Optional, unlimited by default.
+     * - TUNNEL_CATEGORY_PRIORITIES : Comma separated list of `<data category>=<priority>` entries,
+     *   where the priority is low, normal or high. Optional, errors, crash reports and user
+     *   feedback are high, replays and profiles low, and the other categories normal by default.
+     * - TUNNEL_CIRCUIT_BREAKER : When true, requests to a relay fail fast once too many of the
+     *   recent ones failed, or are spooled when there is a spool. Optional, false by default.
+     * - TUNNEL_BREAKER_WINDOW : Number of recent requests checked. Optional, 20 by default.
+     * - TUNNEL_BREAKER_FAILURE_RATIO : Ratio of failed requests opening the breaker. Optional, 0.5
+     *   by default.
+     * - TUNNEL_BREAKER_SLOW_MS : Requests slower than this many milliseconds count as failures.
+     *   Optional, 5000 by default.
+     * - TUNNEL_BREAKER_OPEN_SECS : How long the breaker stays open before probing the relay.
+     *   Optional, 30 by default.
+     * - TUNNEL_RELAYS : Comma separated list of relay urls that envelopes are spread over, whatever
+     *   the host of their dsn. Routes with a target are still sent to their target. Optional, by
+     *   default envelopes are sent to the host of their dsn.
+     * - TUNNEL_RELAY_BALANCING : `round-robin` or `least-outstanding`. Optional, `round-robin` by
+     *   default.
+     * - TUNNEL_CONNECT_TIMEOUT_MS : Maximum time to connect to a relay, in milliseconds. Optional,
+     *   10000 by default.
+     * - TUNNEL_READ_TIMEOUT_MS : A request to a relay is aborted when no data was sent or received
+     *   for this many milliseconds. Optional, 30000 by default.
+     * - TUNNEL_TIMEOUT_MS : Maximum time of a request to a relay, in milliseconds. Optional, 60000
+     *   by default.
+     * - TUNNEL_MAX_CONNECTIONS_PER_HOST : Maximum number of connections to each relay. Optional,
+     *   unlimited by default.
+     * - TUNNEL_HTTP_VERSION : `auto`, `http1` or `http2`. Optional, `auto` by default, which uses
+     *   HTTP/2 when the relay announces it over TLS.
+     * - TUNNEL_PROXY : Url of the proxy that requests to the relays go through, with an `http`,
+     *   `https`, `socks5` or `socks5h` scheme. Optional.
+     * - TUNNEL_NO_PROXY : Comma separated list of relay hosts reached without the proxy. Optional.
+     * - TUNNEL_DNS_OVERRIDES : Comma separated list of `<host>:<port>=<ip>` entries, giving the
+     *   address of relay hosts instead of resolving them. Optional.
      */
     pub fn new_from_env_variables() -> Result<Config, String> {
         let mut options = ListOptions::new();
         options.separator = Some(",".to_string());
-        let remote_hosts  = envmnt::get_list_with_options("TUNNEL_REMOTE_HOST", &options).ok_or_else(|| "Missing sentry remote. Please set the environnement variable 'TUNNEL_REMOTE_HOST' to specify the sentry remote.".to_string())?;
-        let project_ids = envmnt::get_list_with_options("TUNNEL_PROJECT_IDS", &options)
-            .ok_or_else(|| {
-                "Project ID unspecified. Use 'export TUNNEL_PROJECT_IDS' to provide valid ids."
-                    .to_string()
-            })?;
+        let routes = envmnt::get_list_with_options("TUNNEL_ROUTES", &options).unwrap_or_default();
+        let routes = Config::parse_routes(&routes)?;
+        let allowed_dsns = envmnt::get_list_with_options("TUNNEL_ALLOWED_DSNS", &options)
+            .unwrap_or_default()
+            .iter()
+            .map(|dsn| {
+                Dsn::from_str(dsn.trim()).map_err(|e| format!("{} is not a valid dsn : {}", dsn, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let org_ids = envmnt::get_list_with_options("TUNNEL_ORG_IDS", &options)
+            .unwrap_or_default()
+            .iter()
+            .map(|id| {
+                u64::from_str(id.trim().trim_start_matches('o'))
+                    .map_err(|_| format!("{} is not a valid organization id", id))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let dsn_rewrites =
+            envmnt::get_list_with_options("TUNNEL_DSN_REWRITES", &options).unwrap_or_default();
+        let dsn_rewrites = Config::parse_dsn_rewrites(&dsn_rewrites)?;
+        let explicit = !routes.is_empty() || !allowed_dsns.is_empty();
+        let remote_hosts = match envmnt::get_list_with_options("TUNNEL_REMOTE_HOST", &options) {
+            Some(remote_hosts) => remote_hosts,
+            None if explicit => vec![],
+            None => return Err("Missing sentry remote. Please set the environnement variable 'TUNNEL_REMOTE_HOST' to specify the sentry remote.".to_string()),
+        };
+        let project_ids =
+            match envmnt::get_list_with_options("TUNNEL_PROJECT_IDS", &options) {
+                Some(project_ids) => project_ids,
+                None if explicit => vec![],
+                None => return Err(
+                    "Project ID unspecified. Use 'export TUNNEL_PROJECT_IDS' to provide valid ids."
+                        .to_string(),
+                ),
+            };
         let port = envmnt::get_u16("TUNNEL_LISTEN_PORT", 7878);
         let tunnel_path: String =
             envmnt::get_parse("TUNNEL_PATH").unwrap_or_else(|_| "/tunnel".to_string());
         let ip: String = envmnt::get_parse("TUNNEL_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
-        let valid_remote_hosts = Config::clean_remote_hosts(&remote_hosts);
-        if valid_remote_hosts.len() == 0 {
+        let compressions = envmnt::get_list_with_options("TUNNEL_UPSTREAM_COMPRESSION", &options)
+            .unwrap_or_default();
+        let (default_compression, host_compression) = Config::parse_compressions(&compressions)?;
+        let compression_threshold =
+            envmnt::get_usize("TUNNEL_UPSTREAM_COMPRESSION_THRESHOLD", 1024);
+        let stream_bodies = envmnt::is_or("TUNNEL_STREAM_BODIES", false);
+        let max_content_size = envmnt::get_u64("TUNNEL_MAX_CONTENT_SIZE", MAX_CONTENT_SIZE);
+        let default_dsn = match envmnt::get_or("TUNNEL_DEFAULT_DSN", "").trim() {
+            "" => None,
+            dsn => Some(
+                Dsn::from_str(dsn).map_err(|e| format!("{} is not a valid dsn : {}", dsn, e))?,
+            ),
+        };
+        let ip_rate_limit = Config::parse_rate_limit("TUNNEL_RATE_LIMIT_IP")?;
+        let project_rate_limit = Config::parse_rate_limit("TUNNEL_RATE_LIMIT_PROJECT")?;
+        let origin_rate_limit = Config::parse_rate_limit("TUNNEL_RATE_LIMIT_ORIGIN")?;
+        let default_retry = RetryPolicy::default();
+        let retry = RetryPolicy {
+            max_attempts: envmnt::get_u32("TUNNEL_RETRY_ATTEMPTS", default_retry.max_attempts)
+                .max(1),
+            initial_backoff: Duration::from_millis(envmnt::get_u64(
+                "TUNNEL_RETRY_BACKOFF_MS",
+                default_retry.initial_backoff.as_millis() as u64,
+            )),
+            max_backoff: Duration::from_millis(envmnt::get_u64(
+                "TUNNEL_RETRY_MAX_BACKOFF_MS",
+                default_retry.max_backoff.as_millis() as u64,
+            )),
+            deadline: Duration::from_millis(envmnt::get_u64(
+                "TUNNEL_RETRY_DEADLINE_MS",
+                default_retry.deadline.as_millis() as u64,
+            )),
+            retry_timeouts: envmnt::is_or("TUNNEL_RETRY_TIMEOUTS", default_retry.retry_timeouts),
+        };
+        let spool = Config::parse_spool()?;
+        let queue = match envmnt::get_usize("TUNNEL_ASYNC_QUEUE_SIZE", 0) {
+            0 => None,
+            capacity => Some(QueueConfig {
+                capacity,
+                workers: envmnt::get_usize("TUNNEL_ASYNC_WORKERS", 4),
+                when_full: match envmnt::get_or("TUNNEL_ASYNC_QUEUE_FULL", "").trim() {
+                    "" => QueueFullPolicy::Reject,
+                    policy => QueueFullPolicy::from_str(policy)?,
+                },
+            }),
+        };
+        let max_in_flight = match envmnt::get_usize("TUNNEL_MAX_IN_FLIGHT", 0) {
+            0 => None,
+            max_in_flight => Some(max_in_flight),
+        };
+        let category_priorities =
+            envmnt::get_list_with_options("TUNNEL_CATEGORY_PRIORITIES", &options)
+                .unwrap_or_default();
+        let category_priorities = Config::parse_category_priorities(&category_priorities)?;
+        let breaker = Config::parse_breaker()?;
+        let relays = envmnt::get_list_with_options("TUNNEL_RELAYS", &options).unwrap_or_default();
+        let relays = Config::clean_remote_hosts(&relays)?;
+        let balancing = match envmnt::get_or("TUNNEL_RELAY_BALANCING", "").trim() {
+            "" => Balancing::RoundRobin,
+            balancing => Balancing::from_str(balancing)?,
+        };
+        let client = Config::parse_client()?;
+        let valid_remote_hosts = Config::clean_remote_hosts(&remote_hosts)?;
+        if valid_remote_hosts.is_empty() && !explicit {
             Err("No remote hosts to forward sentry envelopes to".to_string())
         } else {
             Ok(Config {
@@ -64,6 +551,28 @@ impl Config {
                 port,
                 tunnel_path,
                 ip,
+                default_compression,
+                host_compression,
+                compression_threshold,
+                stream_bodies,
+                max_content_size,
+                default_dsn,
+                routes,
+                allowed_dsns,
+                org_ids,
+                dsn_rewrites,
+                ip_rate_limit,
+                project_rate_limit,
+                origin_rate_limit,
+                retry,
+                spool,
+                queue,
+                max_in_flight,
+                category_priorities,
+                breaker,
+                relays,
+                balancing,
+                client,
             })
         }
     }
@@ -73,20 +582,222 @@ impl Config {
         self.project_ids.contains(&id_str)
     }
 
-    pub fn clean_remote_hosts(hosts : &[String]) -> Vec<Host>{
-        let mut result = vec!();
-        for host in hosts {
-            if let Ok(host_url) = Url::parse(host) {
-                if let Some(hostname) = host_url.host_str() {
-                    result.push(Host(hostname.to_string()))
-                } else {
-                    error!("{} is not an URL to a remote host", host_url)
+    /**
+     * Returns true when no organization ids are configured, or when the hostname of this dsn
+     * carries one of them
+     */
+    pub fn org_id_is_allowed(&self, dsn: &Dsn) -> bool {
+        self.org_ids.is_empty()
+            || Host::from_dsn(dsn)
+                .org_id()
+                .is_some_and(|id| self.org_ids.contains(&id))
+    }
+
+    /**
+     * Returns true when the public key, project id and host of this dsn match an allowed dsn
+     */
+    pub fn dsn_is_allowed(&self, dsn: &Dsn) -> bool {
+        let host = Host::from_dsn(dsn);
+        self.allowed_dsns.iter().any(|allowed| {
+            allowed.public_key() == dsn.public_key()
+                && allowed.project_id() == dsn.project_id()
+                && Host::from_dsn(allowed) == host
+        })
+    }
+
+    /**
+     * Parse a list of relay urls, failing on the first invalid one
+     */
+    pub fn clean_remote_hosts(hosts: &[String]) -> Result<Vec<Host>, String> {
+        hosts.iter().map(|host| Host::from_str(host)).collect()
+    }
+
+    /**
+     * Returns the compression to use when forwarding envelopes to this host
+     */
+    pub fn compression_for(&self, host: &Host) -> UpstreamCompression {
+        self.host_compression
+            .get(host)
+            .copied()
+            .unwrap_or(self.default_compression)
+    }
+
+    fn parse_compressions(
+        entries: &[String],
+    ) -> Result<(UpstreamCompression, HashMap<Host, UpstreamCompression>), String> {
+        let mut default_compression = UpstreamCompression::None;
+        let mut host_compression = HashMap::new();
+        for entry in entries {
+            match entry.rsplit_once('=') {
+                Some((host, compression)) => {
+                    host_compression.insert(
+                        Host::from_str(host)?,
+                        UpstreamCompression::from_str(compression)?,
+                    );
                 }
-            }
-            else {
-                error!("{} is not a valid url", host)
+                None => default_compression = UpstreamCompression::from_str(entry)?,
             }
         }
-        return result
+        Ok((default_compression, host_compression))
+    }
+
+    fn parse_client() -> Result<ClientConfig, String> {
+        let mut options = ListOptions::new();
+        options.separator = Some(",".to_string());
+        let default = ClientConfig::default();
+        let millis = |variable: &str, default: Duration| {
+            Duration::from_millis(envmnt::get_u64(variable, default.as_millis() as u64))
+        };
+        Ok(ClientConfig {
+            connect_timeout: millis("TUNNEL_CONNECT_TIMEOUT_MS", default.connect_timeout),
+            read_timeout: millis("TUNNEL_READ_TIMEOUT_MS", default.read_timeout),
+            timeout: millis("TUNNEL_TIMEOUT_MS", default.timeout),
+            max_connections_per_host: envmnt::get_usize(
+                "TUNNEL_MAX_CONNECTIONS_PER_HOST",
+                default.max_connections_per_host,
+            ),
+            version: match envmnt::get_or("TUNNEL_HTTP_VERSION", "").trim() {
+                "" => default.version,
+                version => HttpVersion::from_str(version)?,
+            },
+            proxy: match envmnt::get_or("TUNNEL_PROXY", "").trim() {
+                "" => None,
+                proxy => Some(parse_proxy(proxy)?),
+            },
+            no_proxy: envmnt::get_list_with_options("TUNNEL_NO_PROXY", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            dns_overrides: envmnt::get_list_with_options("TUNNEL_DNS_OVERRIDES", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| DnsOverride::from_str(entry))
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+
+    fn parse_breaker() -> Result<Option<BreakerConfig>, String> {
+        if !envmnt::is_or("TUNNEL_CIRCUIT_BREAKER", false) {
+            return Ok(None);
+        }
+        let default = BreakerConfig::default();
+        let failure_ratio: f64 =
+            envmnt::get_parse_or("TUNNEL_BREAKER_FAILURE_RATIO", default.failure_ratio)
+                .map_err(|_| "TUNNEL_BREAKER_FAILURE_RATIO is not a valid ratio".to_string())?;
+        if !(failure_ratio > 0.0 && failure_ratio <= 1.0) {
+            return Err(format!(
+                "{} is not a valid failure ratio, use a number between 0 and 1",
+                failure_ratio
+            ));
+        }
+        Ok(Some(BreakerConfig {
+            window: envmnt::get_usize("TUNNEL_BREAKER_WINDOW", default.window).max(1),
+            failure_ratio,
+            slow_threshold: Duration::from_millis(envmnt::get_u64(
+                "TUNNEL_BREAKER_SLOW_MS",
+                default.slow_threshold.as_millis() as u64,
+            )),
+            open_duration: Duration::from_secs(envmnt::get_u64(
+                "TUNNEL_BREAKER_OPEN_SECS",
+                default.open_duration.as_secs(),
+            )),
+        }))
+    }
+
+    fn parse_category_priorities(entries: &[String]) -> Result<HashMap<String, Priority>, String> {
+        entries
+            .iter()
+            .map(|entry| match entry.split_once('=') {
+                Some((category, priority)) => {
+                    Ok((category.trim().to_string(), Priority::from_str(priority)?))
+                }
+                None => Err(format!(
+                    "{} is not a valid category priority, use <data category>=<priority>",
+                    entry
+                )),
+            })
+            .collect()
+    }
+
+    /**
+     * Returns the route of this public key, or of this project id
+     */
+    pub fn route_for(&self, public_key: &str, project_id: u64) -> Option<&Route> {
+        self.routes
+            .get(public_key)
+            .or_else(|| self.routes.get(&project_id.to_string()))
+    }
+
+    /**
+     * Returns the host that envelopes for this dsn are sent to
+     */
+    pub fn target_for(&self, dsn: &Dsn) -> Host {
+        match self.route_for(dsn.public_key(), dsn.project_id().value()) {
+            Some(route) => route.target.as_ref().unwrap_or(&route.upstream).clone(),
+            None => Host::from_dsn(dsn),
+        }
+    }
+
+    fn parse_routes(entries: &[String]) -> Result<HashMap<String, Route>, String> {
+        let mut routes = HashMap::new();
+        for entry in entries {
+            let (key, route) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not a valid route", entry))?;
+            routes.insert(key.trim().to_string(), Route::from_str(route)?);
+        }
+        Ok(routes)
+    }
+
+    /**
+     * Returns the dsn that replaces this one when forwarding, if any
+     */
+    pub fn rewrite_for(&self, dsn: &Dsn) -> Option<&Dsn> {
+        self.dsn_rewrites
+            .get(&(dsn.public_key().to_string(), dsn.project_id().value()))
+    }
+
+    fn parse_dsn_rewrites(entries: &[String]) -> Result<HashMap<(String, u64), Dsn>, String> {
+        let mut rewrites = HashMap::new();
+        for entry in entries {
+            let invalid = || format!("{} is not a valid dsn rewrite", entry);
+            let (from, dsn) = entry.split_once('=').ok_or_else(invalid)?;
+            let (public_key, project_id) = from.trim().split_once('/').ok_or_else(invalid)?;
+            let project_id = u64::from_str(project_id).map_err(|_| invalid())?;
+            let dsn = Dsn::from_str(dsn.trim())
+                .map_err(|e| format!("{} is not a valid dsn : {}", dsn, e))?;
+            rewrites.insert((public_key.to_string(), project_id), dsn);
+        }
+        Ok(rewrites)
+    }
+
+    fn parse_rate_limit(variable: &str) -> Result<Option<BucketConfig>, String> {
+        match envmnt::get_or(variable, "").trim() {
+            "" => Ok(None),
+            value => BucketConfig::from_str(value).map(Some),
+        }
+    }
+
+    fn parse_spool() -> Result<Option<SpoolConfig>, String> {
+        let dir = match envmnt::get_or("TUNNEL_SPOOL_DIR", "").trim() {
+            "" => return Ok(None),
+            dir => PathBuf::from(dir),
+        };
+        let default = SpoolConfig::new(dir);
+        Ok(Some(SpoolConfig {
+            max_size: envmnt::get_u64("TUNNEL_SPOOL_MAX_SIZE", default.max_size),
+            max_age: Duration::from_secs(envmnt::get_u64(
+                "TUNNEL_SPOOL_MAX_AGE_SECS",
+                default.max_age.as_secs(),
+            )),
+            segment_size: envmnt::get_u64("TUNNEL_SPOOL_SEGMENT_SIZE", default.segment_size),
+            eviction: match envmnt::get_or("TUNNEL_SPOOL_EVICTION", "").trim() {
+                "" => default.eviction,
+                eviction => EvictionPolicy::from_str(eviction)?,
+            },
+            ..default
+        }))
     }
 }
@@ -1,11 +1,18 @@
-use crate::config::Host;
+use crate::compression::UpstreamCompression;
+use crate::config::{Config, Host};
+use crate::retry::DeadlineExceeded;
+use crate::server::MAX_RESPONSE_SIZE;
+use crate::upstream::Upstream;
+use bytes::Bytes;
+use futures_util::io::{AsyncReadExt, Cursor};
 use gotham::anyhow::Error as AError;
 use gotham::handler::IntoResponse;
 use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::HeaderValue;
 use gotham::hyper::StatusCode;
 use gotham::hyper::{body::Body, Response};
 use gotham::state::State;
-use isahc::{Request, RequestExt};
+use isahc::{AsyncBody, HttpClient, Request};
 use mime::Mime;
 use sentry_types::Dsn;
 use serde_json::Value;
@@ -15,14 +22,166 @@ use log::*;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Instant;
 
 /**
  * Represent a sentry envelope
  */
 #[derive(Debug)]
 pub struct SentryEnvelope {
-    pub raw_body: String,
+    pub raw_body: Bytes,
     pub dsn: Dsn,
+    pub header: Value,
+    pub items: Vec<EnvelopeItem>,
+}
+
+/**
+ * The header and the raw payload of an envelope item. The payload shares the memory of the
+ * envelope body, and may contain arbitrary binary data.
+ */
+#[derive(Debug)]
+pub struct ItemData {
+    pub header: Value,
+    pub payload: Bytes,
+    json: OnceLock<Option<Value>>,
+}
+
+/**
+ * A typed envelope item. Items with a type that this tunnel does not know about are kept as
+ * `Unknown` and forwarded untouched.
+ */
+#[derive(Debug)]
+pub enum EnvelopeItem {
+    Event(ItemData),
+    Transaction(ItemData),
+    Session(ItemData),
+    Sessions(ItemData),
+    Attachment(ItemData),
+    ClientReport(ItemData),
+    ReplayEvent(ItemData),
+    ReplayRecording(ItemData),
+    Profile(ItemData),
+    CheckIn(ItemData),
+    UserReport(ItemData),
+    Log(ItemData),
+    Unknown(ItemData),
+}
+
+impl EnvelopeItem {
+    /**
+     * Build a typed item from its header, using the `type` key of the header
+     */
+    pub fn new(header: Value, payload: Bytes) -> EnvelopeItem {
+        let item_type = header.get("type").and_then(Value::as_str).unwrap_or("");
+        let constructor = match item_type {
+            "event" => EnvelopeItem::Event,
+            "transaction" => EnvelopeItem::Transaction,
+            "session" => EnvelopeItem::Session,
+            "sessions" => EnvelopeItem::Sessions,
+            "attachment" => EnvelopeItem::Attachment,
+            "client_report" => EnvelopeItem::ClientReport,
+            "replay_event" => EnvelopeItem::ReplayEvent,
+            "replay_recording" => EnvelopeItem::ReplayRecording,
+            "profile" => EnvelopeItem::Profile,
+            "check_in" => EnvelopeItem::CheckIn,
+            "user_report" => EnvelopeItem::UserReport,
+            "log" => EnvelopeItem::Log,
+            _ => EnvelopeItem::Unknown,
+        };
+        constructor(ItemData {
+            header,
+            payload,
+            json: OnceLock::new(),
+        })
+    }
+
+    pub fn data(&self) -> &ItemData {
+        match self {
+            EnvelopeItem::Event(data)
+            | EnvelopeItem::Transaction(data)
+            | EnvelopeItem::Session(data)
+            | EnvelopeItem::Sessions(data)
+            | EnvelopeItem::Attachment(data)
+            | EnvelopeItem::ClientReport(data)
+            | EnvelopeItem::ReplayEvent(data)
+            | EnvelopeItem::ReplayRecording(data)
+            | EnvelopeItem::Profile(data)
+            | EnvelopeItem::CheckIn(data)
+            | EnvelopeItem::UserReport(data)
+            | EnvelopeItem::Log(data)
+            | EnvelopeItem::Unknown(data) => data,
+        }
+    }
+
+    /**
+     * The `type` of this item, as written in its header
+     */
+    pub fn item_type(&self) -> &str {
+        self.header()
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("")
+    }
+
+    /**
+     * The sentry data category of this item, used by rate limits
+     */
+    pub fn data_category(&self) -> &'static str {
+        match self {
+            EnvelopeItem::Event(_) => "error",
+            EnvelopeItem::Transaction(_) => "transaction",
+            EnvelopeItem::Session(_) | EnvelopeItem::Sessions(_) => "session",
+            EnvelopeItem::Attachment(_) => "attachment",
+            EnvelopeItem::ClientReport(_) => "internal",
+            EnvelopeItem::ReplayEvent(_) | EnvelopeItem::ReplayRecording(_) => "replay",
+            EnvelopeItem::Profile(_) => "profile",
+            EnvelopeItem::CheckIn(_) => "monitor",
+            EnvelopeItem::UserReport(_) => "user_report",
+            EnvelopeItem::Log(_) => "log_item",
+            EnvelopeItem::Unknown(_) => "unknown",
+        }
+    }
+
+    pub fn header(&self) -> &Value {
+        &self.data().header
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.data().payload
+    }
+
+    /**
+     * The JSON payload of this item, parsed on first access. Returns None for items that do not
+     * carry JSON (attachments, replay recordings and unknown items), or when the payload is not
+     * valid JSON.
+     */
+    pub fn json(&self) -> Option<&Value> {
+        match self {
+            EnvelopeItem::Attachment(_)
+            | EnvelopeItem::ReplayRecording(_)
+            | EnvelopeItem::Unknown(_) => None,
+            _ => {
+                let data = self.data();
+                data.json
+                    .get_or_init(|| serde_json::from_slice(&data.payload).ok())
+                    .as_ref()
+            }
+        }
+    }
+}
+
+/**
+ * The answer of a sentry relay to a forwarded envelope, passed back to the client so that SDKs
+ * see the status codes and rate limits of sentry
+ */
+#[derive(Debug)]
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub retry_after: Option<HeaderValue>,
+    pub rate_limits: Option<HeaderValue>,
+    pub content_type: Option<HeaderValue>,
+    pub body: Bytes,
 }
 
 /**
@@ -30,26 +189,44 @@ pub struct SentryEnvelope {
  */
 #[derive(Debug)]
 pub enum BodyError {
-    InvalidNumberOfLines,
+    MissingEnvelopeHeader,
     InvalidHeaderJson(serde_json::Error),
+    InvalidItemHeaderJson(serde_json::Error),
+    InvalidEventJson(serde_json::Error),
+    InvalidItemLength,
+    UnexpectedEndOfBody,
     MissingDsnKeyInHeader,
     InvalidDsnValue,
     InvalidProjectId,
+    DsnNotAllowed,
+    ProjectIdMismatch,
 }
 
 impl Display for BodyError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            BodyError::InvalidNumberOfLines => {
-                f.write_str("Invalid number of line in request body. Should be 3.")
-            }
+            BodyError::MissingEnvelopeHeader => f.write_str("The envelope header is missing"),
             BodyError::MissingDsnKeyInHeader => {
                 f.write_str("The dsn key is missing from the header header")
             }
             BodyError::InvalidHeaderJson(e) => {
                 f.write_fmt(format_args!("Failed to parse header json : {}", e))
             }
+            BodyError::InvalidItemHeaderJson(e) => {
+                f.write_fmt(format_args!("Failed to parse item header json : {}", e))
+            }
+            BodyError::InvalidEventJson(e) => {
+                f.write_fmt(format_args!("Failed to parse event json : {}", e))
+            }
+            BodyError::InvalidItemLength => f.write_str("Invalid item length in item header"),
+            BodyError::UnexpectedEndOfBody => {
+                f.write_str("The body ended before the end of an item payload")
+            }
             BodyError::InvalidProjectId => f.write_str("Unauthorized project ID"),
+            BodyError::DsnNotAllowed => f.write_str("Unauthorized dsn"),
+            BodyError::ProjectIdMismatch => {
+                f.write_str("The project ID of the dsn does not match the request path")
+            }
             BodyError::InvalidDsnValue => f.write_str("Failed to parse dsn value"),
         }
     }
@@ -70,56 +247,403 @@ impl SentryEnvelope {
      * Returns true if this envelope is for an host that we are allowed to forward requests to
      */
     pub fn dsn_host_is_valid(&self, host: &[Host]) -> bool {
-        let envelope_host = self.dsn.host().to_string();
-        host.iter()
-            .any(|x| x.0 == envelope_host)
+        dsn_host_is_valid(&self.dsn, host)
     }
 
     /**
-     * Forward this envelope to the destination sentry relay
+     * Replace the dsn of this envelope, in its header and in its raw body
      */
-    pub async fn forward(&self) -> Result<(), AError> {
-        let uri = self.dsn.envelope_api_url().to_string() + "?sentry_key=" + self.dsn.public_key();
-        let request = Request::builder()
-            .uri(uri)
-            .header("Content-type", "application/x-sentry-envelope")
-            .method("POST")
-            .body(self.raw_body.clone())?;
-        info!(
-            "Sending HTTP {} {} - body={}",
-            request.method(),
-            request.uri(),
-            request.body()
-        );
-        match request.send_async().await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+    pub fn rewrite_dsn(&mut self, dsn: Dsn) -> Result<(), AError> {
+        let (header_line, _) = next_line(&self.raw_body, 0);
+        self.raw_body = rewrite_header_dsn(&self.raw_body, header_line.len(), &dsn)?;
+        if let Some(header) = self.header.as_object_mut() {
+            header.insert("dsn".to_string(), Value::String(dsn_header_value(&dsn)));
+        }
+        self.dsn = dsn;
+        Ok(())
+    }
+
+    /**
+     * Keep only the items matching `keep`, and rebuild the raw body from the remaining ones
+     */
+    pub fn retain_items<F>(&mut self, keep: F) -> Result<(), AError>
+    where
+        F: Fn(&EnvelopeItem) -> bool,
+    {
+        let before = self.items.len();
+        self.items.retain(keep);
+        if self.items.len() == before {
+            return Ok(());
         }
+        let (header_line, _) = next_line(&self.raw_body, 0);
+        let mut body = header_line.to_vec();
+        body.push(b'\n');
+        for item in &self.items {
+            serde_json::to_writer(&mut body, item.header())?;
+            body.push(b'\n');
+            body.extend_from_slice(item.payload());
+            body.push(b'\n');
+        }
+        self.raw_body = Bytes::from(body);
+        Ok(())
+    }
+
+    /**
+     * Forward this envelope to the destination sentry relay
+     */
+    pub async fn forward(
+        &self,
+        config: &Config,
+        upstream: &Upstream,
+    ) -> Result<UpstreamResponse, AError> {
+        forward_raw(config, upstream, &self.dsn, &self.raw_body).await
+    }
+
+    /**
+     * Attempt to parse a request body into an envelope.
+     *
+     * The body is made of an envelope header, followed by any number of items. Each item is an
+     * item header followed by its payload. When the item header has a `length`, exactly that many
+     * bytes are read as the payload, otherwise the payload runs until the next newline. The
+     * trailing newline is optional.
+     *
+     * Only the headers have to be valid JSON, payloads are kept as raw bytes.
+     */
+    pub fn try_new_from_body(body: Bytes) -> Result<SentryEnvelope, AError> {
+        SentryEnvelope::try_new_from_body_with_fallback(body, None)
     }
 
     /**
-     * Attempt to parse a string into an envelope
+     * Same as `try_new_from_body`, but envelopes without a dsn in their header use `fallback_dsn`
      */
-    pub fn try_new_from_body(body: String) -> Result<SentryEnvelope, AError> {
-        if body.lines().count() == 3 {
-            let header = body.lines().next().ok_or(BodyError::InvalidNumberOfLines)?;
-            let header: Value =
-                serde_json::from_str(header).map_err(|e| BodyError::InvalidHeaderJson(e))?;
-            if let Some(dsn) = header.get("dsn") {
-                if let Some(dsn_str) = dsn.as_str() {
-                    let dsn = Dsn::from_str(dsn_str)?;
-                    Ok(SentryEnvelope {
-                        dsn,
-                        raw_body: body,
-                    })
-                } else {
-                    Err(AError::new(BodyError::InvalidDsnValue))
+    pub fn try_new_from_body_with_fallback(
+        body: Bytes,
+        fallback_dsn: Option<Dsn>,
+    ) -> Result<SentryEnvelope, AError> {
+        let (header_line, mut position) = next_line(&body, 0);
+        let (header, dsn) = parse_envelope_header(header_line)?;
+        let dsn = dsn
+            .or(fallback_dsn)
+            .ok_or(BodyError::MissingDsnKeyInHeader)?;
+
+        let mut items = vec![];
+        while position < body.len() {
+            let (item_header_line, payload_start) = next_line(&body, position);
+            if is_blank(item_header_line) {
+                position = payload_start;
+                continue;
+            }
+            let item_header: Value = serde_json::from_slice(item_header_line)
+                .map_err(BodyError::InvalidItemHeaderJson)?;
+            let payload = match item_header.get("length") {
+                Some(length) => {
+                    let length = length.as_u64().ok_or(BodyError::InvalidItemLength)? as usize;
+                    let payload_end = payload_start
+                        .checked_add(length)
+                        .ok_or(BodyError::InvalidItemLength)?;
+                    if payload_end > body.len() {
+                        return Err(AError::new(BodyError::UnexpectedEndOfBody));
+                    }
+                    position = payload_end;
+                    match body.get(position) {
+                        None => {}
+                        Some(b'\n') => position += 1,
+                        Some(_) => return Err(AError::new(BodyError::InvalidItemLength)),
+                    }
+                    body.slice(payload_start..payload_end)
+                }
+                None => {
+                    let (payload, next_position) = next_line(&body, payload_start);
+                    position = next_position;
+                    body.slice(payload_start..payload_start + payload.len())
+                }
+            };
+            items.push(EnvelopeItem::new(item_header, payload));
+        }
+
+        Ok(SentryEnvelope {
+            dsn,
+            header,
+            items,
+            raw_body: body,
+        })
+    }
+}
+
+/**
+ * Wrap an event sent to the legacy `store` endpoint into an envelope with a single event item
+ */
+pub fn envelope_from_event(event: &[u8]) -> Result<Bytes, AError> {
+    let event_json: Value = serde_json::from_slice(event).map_err(BodyError::InvalidEventJson)?;
+    let mut header = serde_json::Map::new();
+    if let Some(event_id) = event_json.get("event_id") {
+        header.insert("event_id".to_string(), event_id.clone());
+    }
+    let item_header = serde_json::json!({"type": "event", "length": event.len()});
+
+    let mut envelope = serde_json::to_vec(&header)?;
+    envelope.push(b'\n');
+    envelope.extend(serde_json::to_vec(&item_header)?);
+    envelope.push(b'\n');
+    envelope.extend_from_slice(event);
+    Ok(Bytes::from(envelope))
+}
+
+/**
+ * Parse the first line of an envelope, and extract its dsn if there is one
+ */
+pub fn parse_envelope_header(header_line: &[u8]) -> Result<(Value, Option<Dsn>), AError> {
+    if is_blank(header_line) {
+        return Err(AError::new(BodyError::MissingEnvelopeHeader));
+    }
+    let header: Value =
+        serde_json::from_slice(header_line).map_err(BodyError::InvalidHeaderJson)?;
+    let dsn = match header.get("dsn") {
+        Some(dsn) => match dsn.as_str() {
+            Some(dsn_str) => Some(Dsn::from_str(dsn_str)?),
+            None => return Err(AError::new(BodyError::InvalidDsnValue)),
+        },
+        None => None,
+    };
+    Ok((header, dsn))
+}
+
+/**
+ * Build a dsn pointing to the same sentry instance as `base`, with another public key and project
+ */
+pub fn dsn_with_key_and_project(
+    base: &Dsn,
+    public_key: &str,
+    project_id: u64,
+) -> Result<Dsn, AError> {
+    let dsn = format!(
+        "{}://{}@{}:{}{}{}",
+        base.scheme(),
+        public_key,
+        base.host(),
+        base.port(),
+        base.path(),
+        project_id
+    );
+    Ok(Dsn::from_str(&dsn)?)
+}
+
+/**
+ * Returns true if this dsn is for an host that we are allowed to forward requests to. Hosts can
+ * contain `*` wildcard labels.
+ */
+pub fn dsn_host_is_valid(dsn: &Dsn, host: &[Host]) -> bool {
+    let envelope_host = Host::from_dsn(dsn);
+    host.iter().any(|host| host.matches(&envelope_host))
+}
+
+/**
+ * Replace the dsn of an envelope header line, which spans the first `header_length` bytes of the
+ * body. The rest of the body is copied as is.
+ */
+pub fn rewrite_header_dsn(body: &[u8], header_length: usize, dsn: &Dsn) -> Result<Bytes, AError> {
+    let mut header: Value =
+        serde_json::from_slice(&body[..header_length]).map_err(BodyError::InvalidHeaderJson)?;
+    header
+        .as_object_mut()
+        .ok_or(BodyError::MissingEnvelopeHeader)?
+        .insert("dsn".to_string(), Value::String(dsn_header_value(dsn)));
+    let mut rewritten = serde_json::to_vec(&header)?;
+    rewritten.extend_from_slice(&body[header_length..]);
+    Ok(Bytes::from(rewritten))
+}
+
+/**
+ * Format a dsn the way SDKs write it, without the empty secret key that `Dsn` displays
+ */
+pub fn dsn_header_value(dsn: &Dsn) -> String {
+    let origin =
+        Host::from_dsn(dsn)
+            .to_string()
+            .replacen("://", &format!("://{}@", dsn.public_key()), 1);
+    format!("{}{}{}", origin, dsn.path(), dsn.project_id())
+}
+
+/**
+ * Returns the url of the envelope endpoint of `target`, for this dsn
+ */
+pub fn envelope_url(dsn: &Dsn, target: &Host) -> String {
+    format!(
+        "{}{}api/{}/envelope/?sentry_key={}",
+        target,
+        dsn.path(),
+        dsn.project_id(),
+        dsn.public_key()
+    )
+}
+
+/**
+ * Forward a complete envelope body for this dsn to its sentry relay. The body is compressed when
+ * the relay has a compression configured and the body is bigger than the compression threshold.
+ * Failures that are safe to retry are retried according to the retry policy of the config.
+ *
+ * When the envelope is sent to a pool of relays, a failure that is safe to retry is first sent
+ * again right away to the relays of the pool that were not tried yet. With circuit breakers, the
+ * outcome of every attempt is recorded, and the envelope is not sent while the breaker of the
+ * relay is open.
+ */
+pub async fn forward_raw(
+    config: &Config,
+    upstream: &Upstream,
+    dsn: &Dsn,
+    raw_body: &Bytes,
+) -> Result<UpstreamResponse, AError> {
+    let retry = &config.retry;
+    let started = Instant::now();
+    let mut attempt = 1;
+    let mut tried = vec![];
+    // The body is compressed once for each compression used by the relays it is sent to
+    let mut payloads: Vec<(UpstreamCompression, Bytes)> = vec![];
+    loop {
+        let relay = upstream.relay_for(config, dsn, &tried);
+        let target = relay.host.clone();
+        let mut compression = config.compression_for(&target);
+        if raw_body.len() < config.compression_threshold {
+            compression = UpstreamCompression::None;
+        }
+        // Cloning `Bytes` only increments a reference count, the body itself is not copied
+        let payload = match payloads.iter().find(|(used, _)| *used == compression) {
+            Some((_, payload)) => payload.clone(),
+            None => {
+                let payload = compression
+                    .compress_async(raw_body.clone())
+                    .await?
+                    .unwrap_or_else(|| raw_body.clone());
+                payloads.push((compression, payload.clone()));
+                payload
+            }
+        };
+        let payload_length = payload.len() as u64;
+
+        if let Some(breakers) = &upstream.breakers {
+            breakers.allow(&target)?;
+        }
+        let body = AsyncBody::from_reader_sized(Cursor::new(payload), payload_length);
+        let sent = Instant::now();
+        let forward = forward_body(
+            &upstream.client,
+            dsn,
+            &target,
+            body,
+            compression.content_encoding(),
+            MAX_RESPONSE_SIZE,
+        );
+        let result = if retry.max_attempts > 1 {
+            // An attempt in progress is aborted at the deadline, instead of running until the
+            // client times out
+            let remaining = retry.deadline.saturating_sub(started.elapsed());
+            match tokio::time::timeout(remaining, forward).await {
+                Ok(result) => result,
+                Err(_) => Err(DeadlineExceeded {
+                    deadline: retry.deadline,
                 }
-            } else {
-                Err(AError::new(BodyError::MissingDsnKeyInHeader))
+                .into()),
             }
         } else {
-            Err(AError::new(BodyError::InvalidNumberOfLines))
+            forward.await
+        };
+        if let Some(breakers) = &upstream.breakers {
+            breakers.record(&target, &result, sent.elapsed());
+        }
+        drop(relay);
+        if !retry.is_retryable(&result) {
+            return result;
+        }
+        tried.push(target.clone());
+        let expired = retry.max_attempts > 1 && started.elapsed() >= retry.deadline;
+        if !expired && upstream.can_fail_over(config, dsn, &tried) {
+            warn!(
+                "Failed to forward to {}, failing over to another relay",
+                target
+            );
+            continue;
+        }
+        tried.clear();
+        if attempt >= retry.max_attempts {
+            return result;
         }
+        let backoff = retry.backoff(attempt);
+        if started.elapsed() + backoff >= retry.deadline {
+            return result;
+        }
+        warn!(
+            "Attempt {} to forward to {} failed, retrying in {:?}",
+            attempt, target, backoff
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/**
+ * Send an envelope body for this dsn to the `target` sentry relay with `client`, and read its
+ * answer. At most `max_response_size` bytes of the answer body are read, the rest is dropped.
+ */
+pub async fn forward_body(
+    client: &HttpClient,
+    dsn: &Dsn,
+    target: &Host,
+    body: AsyncBody,
+    content_encoding: Option<&str>,
+    max_response_size: u64,
+) -> Result<UpstreamResponse, AError> {
+    let uri = envelope_url(dsn, target);
+    let mut request = Request::builder()
+        .uri(uri)
+        .header("Content-type", "application/x-sentry-envelope")
+        .method("POST");
+    if let Some(content_encoding) = content_encoding {
+        request = request.header("Content-Encoding", content_encoding);
+    }
+    let request = request.body(body)?;
+    info!(
+        "Sending HTTP {} {} - body={} bytes",
+        request.method(),
+        request.uri(),
+        request
+            .body()
+            .len()
+            .map_or_else(|| "streamed".to_string(), |length| length.to_string())
+    );
+    let mut response = client.send_async(request).await?;
+    let mut body = vec![];
+    response
+        .body_mut()
+        .take(max_response_size.saturating_add(1))
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() as u64 > max_response_size {
+        warn!(
+            "The answer of {} is bigger than {} bytes, it is truncated",
+            target, max_response_size
+        );
+        body.truncate(max_response_size as usize);
+    }
+    let headers = response.headers();
+    Ok(UpstreamResponse {
+        status: response.status(),
+        retry_after: headers.get("Retry-After").cloned(),
+        rate_limits: headers.get("X-Sentry-Rate-Limits").cloned(),
+        content_type: headers.get("Content-Type").cloned(),
+        body: Bytes::from(body),
+    })
+}
+
+/**
+ * Returns the line starting at `start` (without its newline) and the position right after it
+ */
+fn next_line(body: &[u8], start: usize) -> (&[u8], usize) {
+    match body[start..].iter().position(|b| *b == b'\n') {
+        Some(offset) => (&body[start..start + offset], start + offset + 1),
+        None => (&body[start..], body.len()),
     }
 }
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(|b| b.is_ascii_whitespace())
+}
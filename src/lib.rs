@@ -1,3 +1,14 @@
+pub mod breaker;
+pub mod client;
+pub mod compression;
 pub mod config;
 pub mod envelope;
+pub mod pool;
+pub mod queue;
+pub mod rate_limits;
+pub mod retry;
 pub mod server;
+pub mod shedding;
+pub mod spool;
+pub mod throttle;
+pub mod upstream;
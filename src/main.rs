@@ -21,9 +21,15 @@ pub async fn main() {
                 println!("Ctrl+C pressed");
             };
 
-            let server = gotham::init_server(addr, move || {
-                Ok(router(&config.tunnel_path.clone(), config.clone()))
-            });
+            // The router is built once, so that every request shares its state
+            let router = match router(&config.tunnel_path.clone(), config) {
+                Ok(router) => router,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1)
+                }
+            };
+            let server = gotham::init_server(addr, router);
             let res = future::select(server.boxed(), signal.boxed()).await;
             if let Either::Left((Err(err), _)) = res {
                 println!("Error starting gotham: {:?}", err);
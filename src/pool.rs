@@ -0,0 +1,156 @@
+use crate::config::Host;
+use serde_json::{json, Value};
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/**
+ * How the relay of each request is picked in a pool of relays
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Balancing {
+    /// Each relay in turn
+    RoundRobin,
+    /// The relay with the fewest requests in progress
+    LeastOutstanding,
+}
+
+impl FromStr for Balancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "least-outstanding" => Ok(Balancing::LeastOutstanding),
+            other => Err(format!(
+                "{} is not a valid balancing, use round-robin or least-outstanding",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Balancing {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Balancing::RoundRobin => "round-robin",
+            Balancing::LeastOutstanding => "least-outstanding",
+        })
+    }
+}
+
+/**
+ * A set of interchangeable relays, which envelopes are spread over whatever the host of their dsn
+ */
+#[derive(Debug)]
+pub struct RelayPool {
+    relays: Vec<Host>,
+    balancing: Balancing,
+    next: AtomicUsize,
+    outstanding: Vec<AtomicUsize>,
+    sent: Vec<AtomicU64>,
+}
+
+/**
+ * A relay picked from the pool. It counts as having a request in progress until dropped.
+ */
+#[derive(Debug)]
+pub struct Outstanding<'a> {
+    pool: &'a RelayPool,
+    index: usize,
+}
+
+impl Outstanding<'_> {
+    pub fn host(&self) -> &Host {
+        &self.pool.relays[self.index]
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.pool.outstanding[self.index].fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl RelayPool {
+    pub fn new(relays: Vec<Host>, balancing: Balancing) -> RelayPool {
+        RelayPool {
+            outstanding: relays.iter().map(|_| AtomicUsize::new(0)).collect(),
+            sent: relays.iter().map(|_| AtomicU64::new(0)).collect(),
+            relays,
+            balancing,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn relays(&self) -> &[Host] {
+        &self.relays
+    }
+
+    /**
+     * Pick the relay of the next request. Relays that were already `tried` for this request are
+     * avoided, and so are relays that are not `healthy`, unless there is no other choice.
+     */
+    pub fn pick<F>(&self, healthy: F, tried: &[Host]) -> Option<Outstanding<'_>>
+    where
+        F: Fn(&Host) -> bool,
+    {
+        let count = self.relays.len();
+        if count == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let order = (0..count).map(|offset| (start + offset) % count);
+        let untried = order
+            .clone()
+            .filter(|index| !tried.contains(&self.relays[*index]))
+            .collect::<Vec<_>>();
+        let candidates = if untried.is_empty() {
+            order.collect::<Vec<_>>()
+        } else {
+            untried
+        };
+        let healthy = candidates
+            .iter()
+            .copied()
+            .filter(|index| healthy(&self.relays[*index]))
+            .collect::<Vec<_>>();
+        let candidates = if healthy.is_empty() {
+            candidates
+        } else {
+            healthy
+        };
+        let index = match self.balancing {
+            Balancing::RoundRobin => candidates[0],
+            Balancing::LeastOutstanding => *candidates
+                .iter()
+                .min_by_key(|index| self.outstanding[**index].load(Ordering::Acquire))?,
+        };
+        self.outstanding[index].fetch_add(1, Ordering::AcqRel);
+        self.sent[index].fetch_add(1, Ordering::Relaxed);
+        Some(Outstanding { pool: self, index })
+    }
+
+    /**
+     * The requests in progress and sent to each relay, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        let relays = self
+            .relays
+            .iter()
+            .enumerate()
+            .map(|(index, host)| {
+                json!({
+                    "host": host.to_string(),
+                    "outstanding": self.outstanding[index].load(Ordering::Relaxed),
+                    "sent": self.sent[index].load(Ordering::Relaxed),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "balancing": self.balancing.to_string(),
+            "relays": relays,
+        })
+    }
+}
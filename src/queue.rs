@@ -0,0 +1,263 @@
+use crate::envelope::SentryEnvelope;
+use crate::shedding::{InFlight, Priority};
+use gotham::hyper::StatusCode;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Mutex;
+
+use log::*;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/**
+ * What to do with an envelope when the forwarding queue is full
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueueFullPolicy {
+    /// Answer 503 to the client
+    Reject,
+    /// Answer as if the envelope was queued, and drop it
+    Drop,
+}
+
+impl FromStr for QueueFullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(QueueFullPolicy::Reject),
+            "drop" => Ok(QueueFullPolicy::Drop),
+            other => Err(format!(
+                "{} is not a valid queue full policy, use reject or drop",
+                other
+            )),
+        }
+    }
+}
+
+/**
+ * The size of the in-memory forwarding queue, and the number of workers draining it
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub workers: usize,
+    pub when_full: QueueFullPolicy,
+}
+
+impl Display for QueueConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} envelopes, {} workers, {} when full",
+            self.capacity,
+            self.workers,
+            match self.when_full {
+                QueueFullPolicy::Reject => "reject",
+                QueueFullPolicy::Drop => "drop",
+            }
+        ))
+    }
+}
+
+/**
+ * An error raised when an envelope can not be queued
+ */
+#[derive(Debug)]
+pub enum QueueError {
+    QueueIsFull,
+}
+
+impl QueueError {
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::QueueIsFull => f.write_str("The forwarding queue is full."),
+        }
+    }
+}
+
+impl Error for QueueError {}
+
+/**
+ * A queued envelope, with the slot of the in-flight budget it holds until it is delivered
+ */
+type Queued = (SentryEnvelope, Option<InFlight>);
+
+/**
+ * A bounded in-memory queue of envelopes, forwarded by worker tasks running on a dedicated
+ * thread, so that clients get their answer without waiting for the relay.
+ *
+ * Envelopes are queued by priority : workers always take the highest priority envelope first, and
+ * lower priorities may only fill their share of the queue.
+ */
+#[derive(Debug)]
+pub struct ForwardQueue {
+    config: QueueConfig,
+    // The mutex makes the queue unwind safe, which gotham requires from shared state
+    senders: std::sync::Mutex<[Sender<Queued>; 3]>,
+    length: Arc<AtomicUsize>,
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/**
+ * The receiving ends of the queue, one per priority
+ */
+struct Receivers {
+    low: Receiver<Queued>,
+    normal: Receiver<Queued>,
+    high: Receiver<Queued>,
+}
+
+impl Receivers {
+    /**
+     * Wait for the next envelope, taking the highest priority ones first. Returns None once the
+     * queue is closed.
+     */
+    async fn recv(&mut self) -> Option<Queued> {
+        tokio::select! {
+            biased;
+            Some(queued) = self.high.recv() => Some(queued),
+            Some(queued) = self.normal.recv() => Some(queued),
+            Some(queued) = self.low.recv() => Some(queued),
+            else => None,
+        }
+    }
+}
+
+impl ForwardQueue {
+    /**
+     * Start the workers, which hand every queued envelope to `deliver`
+     */
+    pub fn start<F, Fut>(config: QueueConfig, deliver: F) -> io::Result<ForwardQueue>
+    where
+        F: Fn(SentryEnvelope) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let capacity = config.capacity.max(1);
+        let (low, low_receiver) = mpsc::channel(capacity);
+        let (normal, normal_receiver) = mpsc::channel(capacity);
+        let (high, high_receiver) = mpsc::channel(capacity);
+        let receivers = Arc::new(Mutex::new(Receivers {
+            low: low_receiver,
+            normal: normal_receiver,
+            high: high_receiver,
+        }));
+        let length = Arc::new(AtomicUsize::new(0));
+        let deliver = Arc::new(deliver);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let workers = config.workers.max(1);
+        let workers_length = length.clone();
+        std::thread::Builder::new()
+            .name("forward-queue".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let handles = (0..workers)
+                        .map(|_| {
+                            let receivers = receivers.clone();
+                            let deliver = deliver.clone();
+                            let length = workers_length.clone();
+                            tokio::spawn(async move {
+                                loop {
+                                    let queued = receivers.lock().await.recv().await;
+                                    match queued {
+                                        Some((envelope, in_flight)) => {
+                                            length.fetch_sub(1, Ordering::AcqRel);
+                                            deliver(envelope).await;
+                                            drop(in_flight);
+                                        }
+                                        None => break,
+                                    }
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        let _ = handle.await;
+                    }
+                })
+            })?;
+        Ok(ForwardQueue {
+            config,
+            senders: std::sync::Mutex::new([low, normal, high]),
+            length,
+            queued: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /**
+     * Queue an envelope, along with its slot of the in-flight budget. When its priority has filled
+     * its share of the queue, the envelope is either refused or dropped according to the config.
+     */
+    pub fn push(
+        &self,
+        envelope: SentryEnvelope,
+        priority: Priority,
+        in_flight: Option<InFlight>,
+    ) -> Result<(), QueueError> {
+        let limit = priority.limit(self.config.capacity.max(1));
+        let reserved = self
+            .length
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |length| {
+                (length < limit).then_some(length + 1)
+            })
+            .is_ok();
+        let sent = if reserved {
+            let sent =
+                self.senders.lock().unwrap()[priority as usize].try_send((envelope, in_flight));
+            if sent.is_err() {
+                self.length.fetch_sub(1, Ordering::AcqRel);
+            }
+            sent.is_ok()
+        } else {
+            false
+        };
+        if sent {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        match self.config.when_full {
+            QueueFullPolicy::Reject => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(QueueError::QueueIsFull)
+            }
+            QueueFullPolicy::Drop => {
+                warn!(
+                    "Forwarding queue is full, {} priority envelope dropped",
+                    priority
+                );
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /**
+     * The length and counters of the queue, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        json!({
+            "capacity": self.config.capacity,
+            "length": self.length.load(Ordering::Relaxed),
+            "queued": self.queued.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+            "rejected": self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use crate::envelope::UpstreamResponse;
+use gotham::hyper::StatusCode;
+use sentry_types::Dsn;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Retry delay used when sentry answers 429 without telling how long to wait
+ */
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/**
+ * The rate limits that sentry returned for each dsn, so that envelopes which would be rejected
+ * anyway are not forwarded until the limits expire.
+ *
+ * Limits are stored per data category, the empty category standing for every category.
+ */
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    limits: Mutex<HashMap<String, HashMap<String, Instant>>>,
+}
+
+impl RateLimits {
+    /**
+     * Record the rate limits found in an answer of sentry for this dsn. The
+     * `X-Sentry-Rate-Limits` header is used when present, otherwise a 429 answer limits every
+     * category for the duration of its `Retry-After` header.
+     */
+    pub fn update(&self, dsn: &Dsn, response: &UpstreamResponse) {
+        let now = Instant::now();
+        let mut new_limits = vec![];
+        if let Some(header) = response
+            .rate_limits
+            .as_ref()
+            .and_then(|value| value.to_str().ok())
+        {
+            new_limits = parse_rate_limits(header);
+        } else if response.status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .retry_after
+                .as_ref()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            new_limits.push((retry_after, vec![String::new()]));
+        }
+        if new_limits.is_empty() {
+            return;
+        }
+
+        let mut limits = self.limits.lock().unwrap();
+        let dsn_limits = limits.entry(dsn.to_string()).or_default();
+        for (retry_after, categories) in new_limits {
+            for category in categories {
+                let until = now + retry_after;
+                let current = dsn_limits.entry(category).or_insert(until);
+                *current = (*current).max(until);
+            }
+        }
+        dsn_limits.retain(|_, until| *until > now);
+    }
+
+    /**
+     * Returns how long items of this category are still limited for this dsn, if they are
+     */
+    pub fn limited_for(&self, dsn: &Dsn, category: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let limits = self.limits.lock().unwrap();
+        let dsn_limits = limits.get(&dsn.to_string())?;
+        [category, ""]
+            .iter()
+            .filter_map(|category| dsn_limits.get(*category))
+            .filter(|until| **until > now)
+            .map(|until| *until - now)
+            .max()
+    }
+
+    /**
+     * Returns the active limits of this dsn as `Retry-After` and `X-Sentry-Rate-Limits` header
+     * values, or None when the dsn is not limited
+     */
+    pub fn headers_for(&self, dsn: &Dsn) -> Option<(String, String)> {
+        let now = Instant::now();
+        let limits = self.limits.lock().unwrap();
+        let active = limits
+            .get(&dsn.to_string())?
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(category, until)| (category, (*until - now).as_secs() + 1))
+            .collect::<Vec<_>>();
+        let retry_after = active.iter().map(|(_, seconds)| *seconds).max()?;
+        let rate_limits = active
+            .iter()
+            .map(|(category, seconds)| format!("{}:{}:key", seconds, category))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some((retry_after.to_string(), rate_limits))
+    }
+}
+
+/**
+ * Parse a `X-Sentry-Rate-Limits` header, made of comma separated
+ * `<retry after>:<categories>:<scope>...` entries where categories are separated by `;`. An entry
+ * without categories applies to every category, which is returned as the empty category.
+ */
+pub fn parse_rate_limits(header: &str) -> Vec<(Duration, Vec<String>)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.trim().split(':');
+            let retry_after = parts.next()?.trim().parse::<f64>().ok()?;
+            let retry_after = Duration::try_from_secs_f64(retry_after).ok()?;
+            let mut categories = parts
+                .next()
+                .unwrap_or("")
+                .split(';')
+                .map(str::trim)
+                .filter(|category| !category.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            if categories.is_empty() {
+                categories.push(String::new());
+            }
+            Some((retry_after, categories))
+        })
+        .collect()
+}
@@ -0,0 +1,117 @@
+use crate::breaker::CircuitOpen;
+use crate::envelope::UpstreamResponse;
+use anyhow::Error as AError;
+use gotham::hyper::StatusCode;
+use isahc::error::ErrorKind;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/**
+ * How envelopes are sent again when the sentry relay can not be reached, or answers that it is
+ * temporarily unavailable
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one. 1 disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each attempt
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_backoff: Duration,
+    /// No attempt is started once this much time has passed since the first one, and the
+    /// attempt in progress is aborted
+    pub deadline: Duration,
+    /// Requests that timed out are sent again too, even though the relay may have stored their
+    /// envelope already
+    pub retry_timeouts: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            deadline: Duration::from_secs(10),
+            retry_timeouts: false,
+        }
+    }
+}
+
+impl Display for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} attempts, backoff from {:?} to {:?}, deadline of {:?}",
+            self.max_attempts, self.initial_backoff, self.max_backoff, self.deadline
+        ))?;
+        if self.retry_timeouts {
+            f.write_str(", timeouts retried")?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * The error returned when an attempt was still in progress at the deadline of the retries
+ */
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    pub deadline: Duration,
+}
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "No answer from the relay within the retry deadline of {:?}",
+            self.deadline
+        ))
+    }
+}
+
+impl Error for DeadlineExceeded {}
+
+impl RetryPolicy {
+    /**
+     * The delay to wait after the given failed attempt (starting at 1). The exponential delay is
+     * capped by `max_backoff`, then a random jitter picks a delay between half of it and all of it.
+     */
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        exponential / 2 + exponential.mul_f64(fastrand::f64() / 2.0)
+    }
+
+    /**
+     * Returns true if this result of a forward can be retried : the relay could not be reached, it
+     * answered 502, 503 or 504, or its circuit breaker is open, so the envelope was not stored. A
+     * request that timed out, or was aborted at the deadline, is only retried with
+     * `retry_timeouts`, since sentry may have stored its envelope.
+     */
+    pub fn is_retryable(&self, result: &Result<UpstreamResponse, AError>) -> bool {
+        match result {
+            Ok(response) => matches!(
+                response.status,
+                StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            Err(e) => {
+                let timed_out = e.is::<DeadlineExceeded>()
+                    || e.downcast_ref::<isahc::Error>()
+                        .is_some_and(|e| e.kind() == ErrorKind::Timeout);
+                e.is::<CircuitOpen>()
+                    || (timed_out && self.retry_timeouts)
+                    || e.downcast_ref::<isahc::Error>().is_some_and(|e| {
+                        matches!(
+                            e.kind(),
+                            ErrorKind::ConnectionFailed | ErrorKind::NameResolution
+                        )
+                    })
+            }
+        }
+    }
+}
@@ -1,44 +1,150 @@
 use anyhow::Error as AError;
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, StreamExt, TryStreamExt};
 
 use gotham::handler::HandlerResult;
 use gotham::handler::IntoResponse;
 use gotham::helpers::http::response::create_empty_response;
 use gotham::helpers::http::response::create_response;
-use gotham::hyper::{body, header, Body, HeaderMap, Response, StatusCode};
+use gotham::hyper::header::{HeaderName, HeaderValue};
+use gotham::hyper::{header, Body, HeaderMap, Response, StatusCode, Uri};
 use gotham::middleware::state::StateMiddleware;
 use gotham::pipeline::single::single_pipeline;
 use gotham::pipeline::single_middleware;
 use gotham::router::{
     builder::build_router, builder::DefineSingleRoute, builder::DrawRoutes, Router,
 };
-use gotham::state::{FromState, State};
-use gotham_derive::StateData;
+use gotham::state::{client_addr, FromState, State};
+use gotham_derive::{StateData, StaticResponseExtender};
+use serde::Deserialize;
+use serde_json::json;
 
 use log::*;
 
 use mime::Mime;
 
+use isahc::AsyncBody;
+use sentry_types::{Auth, Dsn};
+
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::envelope::{BodyError, SentryEnvelope};
+use crate::breaker::CircuitOpen;
+use crate::compression::{decode_body_async, DecodingError};
+use crate::config::{Config, Host};
+use crate::envelope::{
+    dsn_header_value, dsn_host_is_valid, dsn_with_key_and_project, envelope_from_event,
+    forward_body, parse_envelope_header, rewrite_header_dsn, BodyError, SentryEnvelope,
+    UpstreamResponse,
+};
+use crate::queue::{ForwardQueue, QueueError};
+use crate::rate_limits::RateLimits;
+use crate::shedding::{envelope_priority, LoadShedder, Priority};
+use crate::spool::{start_drainer, Spool, SpoolError};
+use crate::throttle::Throttle;
+use crate::upstream::Upstream;
 
-// 10 MB max body
+// 10 MB max body, unless configured otherwise
 pub const MAX_CONTENT_SIZE: u64 = 10_000_000;
+// 50 MB max body once decompressed
+pub const MAX_DECOMPRESSED_SIZE: u64 = 50_000_000;
+// A compressed body can not expand to more than 100 times its size
+pub const MAX_COMPRESSION_RATIO: u64 = 100;
+// 64 KB max answer body read from a relay, the rest is dropped
+pub const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
 
 /**
- * This struct is used to share read-only data between HTTP request handlers
+ * This struct is used to share data between HTTP request handlers : the read-only config, the
+ * rate limits returned by sentry, the local rate limits of the tunnel, the spool and the
+ * asynchronous forwarding queue
  */
 #[derive(Debug, StateData, Clone)]
 struct TunnelConfig {
     inner: Arc<Config>,
+    rate_limits: Arc<RateLimits>,
+    throttle: Arc<Throttle>,
+    spool: Option<Arc<Spool>>,
+    queue: Option<Arc<ForwardQueue>>,
+    shedder: Option<Arc<LoadShedder>>,
+    upstream: Arc<Upstream>,
 }
 
-fn parse_body(body: String) -> Result<SentryEnvelope, AError> {
-    SentryEnvelope::try_new_from_body(body)
+/**
+ * The path of the sentry ingest routes : `/api/<project_id>/envelope/` and
+ * `/api/<project_id>/store/`
+ */
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ProjectPath {
+    project_id: u64,
+}
+
+/**
+ * The kind of payload that a route receives
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Endpoint {
+    Envelope,
+    Store,
+}
+
+fn parse_body(body: Bytes, fallback_dsn: Option<Dsn>) -> Result<SentryEnvelope, AError> {
+    SentryEnvelope::try_new_from_body_with_fallback(body, fallback_dsn)
+}
+
+/**
+ * Returns the dsn used for envelopes that do not have one in their header.
+ *
+ * The public key comes from the `sentry_key` query parameter or from the `X-Sentry-Auth` header,
+ * and the project id from the request path. The sentry instance is the upstream of the route of
+ * this key or project. Otherwise the default dsn of the route of the key or project, or else the
+ * one of the config, is used with the key and project that are known.
+ */
+fn fallback_dsn(
+    state: &State,
+    headers: &HeaderMap,
+    config: &Config,
+    path_project_id: Option<u64>,
+) -> Result<Option<Dsn>, AError> {
+    let query_auth = Uri::borrow_from(state)
+        .query()
+        .and_then(|query| Auth::from_querystring(query.as_bytes()).ok());
+    let header_auth = headers
+        .get("X-Sentry-Auth")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Auth::from_str(value).ok());
+    let auth = query_auth.or(header_auth);
+
+    if let (Some(auth), Some(project_id)) = (&auth, path_project_id) {
+        if let Some(route) = config.route_for(auth.public_key(), project_id) {
+            return Ok(Some(route.upstream.dsn(auth.public_key(), project_id)?));
+        }
+    }
+    let route = match (&auth, path_project_id) {
+        (Some(auth), None) => config.routes.get(auth.public_key()),
+        (None, Some(project_id)) => config.routes.get(&project_id.to_string()),
+        _ => None,
+    };
+    let default_dsn = match route
+        .and_then(|route| route.default_dsn.as_ref())
+        .or(config.default_dsn.as_ref())
+    {
+        Some(default_dsn) => default_dsn,
+        None => return Ok(None),
+    };
+    let public_key = auth
+        .map(|auth| auth.public_key().to_string())
+        .unwrap_or_else(|| default_dsn.public_key().to_string());
+    let project_id = path_project_id.unwrap_or_else(|| default_dsn.project_id().value());
+    Ok(Some(dsn_with_key_and_project(
+        default_dsn,
+        &public_key,
+        project_id,
+    )?))
 }
 
 /**
@@ -46,18 +152,26 @@ fn parse_body(body: String) -> Result<SentryEnvelope, AError> {
  */
 #[derive(Debug)]
 pub enum HeaderError {
-    MissingContentLength,
     ContentIsTooBig,
     CouldNotParseContentLength,
     InvalidHost,
+    InvalidOrganization,
 }
 
 impl Error for HeaderError {}
 
+impl HeaderError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HeaderError::ContentIsTooBig => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
 impl Display for HeaderError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            HeaderError::MissingContentLength => f.write_str("Missing content length header."),
             HeaderError::ContentIsTooBig => f.write_str("Content length too big."),
             HeaderError::CouldNotParseContentLength => {
                 f.write_str("could not parse content length header.")
@@ -65,6 +179,7 @@ impl Display for HeaderError {
             HeaderError::InvalidHost => f.write_str(
                 "Invalid sentry host, check your config against the dsn used in the request.",
             ),
+            HeaderError::InvalidOrganization => f.write_str("Unauthorized sentry organization."),
         }
     }
 }
@@ -73,14 +188,15 @@ impl IntoResponse for HeaderError {
     fn into_response(self, state: &State) -> Response<Body> {
         warn!("{}", self);
         let mime = "text/plain".parse::<Mime>().unwrap();
-        create_response(state, StatusCode::BAD_REQUEST, mime, format!("{}", self))
+        create_response(state, self.status_code(), mime, format!("{}", self))
     }
 }
 
 /**
- * Returns Ok if the request associated with those headers can be handled
+ * Returns Ok if the request associated with those headers can be handled. Requests without a
+ * content length are accepted, their size is checked while the body is read.
  */
-fn check_content_length(headers: &HeaderMap) -> Result<(), AError> {
+fn check_content_length(headers: &HeaderMap, max_size: u64) -> Result<(), AError> {
     if let Some(content_length_value) = headers.get(header::CONTENT_LENGTH) {
         let content_length = u64::from_str(
             content_length_value
@@ -88,72 +204,583 @@ fn check_content_length(headers: &HeaderMap) -> Result<(), AError> {
                 .map_err(|_| AError::new(HeaderError::CouldNotParseContentLength))?,
         )
         .map_err(|_| AError::new(HeaderError::CouldNotParseContentLength))?;
-        if content_length > MAX_CONTENT_SIZE {
+        if content_length > max_size {
             return Err(AError::new(HeaderError::ContentIsTooBig));
-        } else {
-            return Ok(());
         }
     }
-    Err(AError::new(HeaderError::MissingContentLength))
+    Ok(())
 }
 
-async fn tunnel_handler(state: &mut State) -> Result<Response<Body>, AError> {
-    let headers = HeaderMap::take_from(state);
-    check_content_length(&headers)?;
+/**
+ * Read the whole request body, failing as soon as it is bigger than `max_size`
+ */
+async fn read_body(mut body: Body, max_size: u64) -> Result<Bytes, AError> {
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if (buffer.len() + chunk.len()) as u64 > max_size {
+            return Err(AError::new(HeaderError::ContentIsTooBig));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer.freeze())
+}
 
-    let full_body = body::to_bytes(Body::take_from(state)).await?;
-    let body_content = String::from_utf8(full_body.to_vec())?;
-    let sentry_instance = parse_body(body_content)?;
+/**
+ * Returns Ok if envelopes for this dsn can be forwarded according to the config. When the request
+ * was sent to an ingest route, the project id of its path must match the dsn.
+ *
+ * The allowed dsns and the routing table take over from the flat lists of project ids and remote
+ * hosts when they are set.
+ */
+fn check_dsn(config: &Config, dsn: &Dsn, path_project_id: Option<u64>) -> Result<(), AError> {
+    if path_project_id.is_some_and(|id| id != dsn.project_id().value()) {
+        Err(AError::new(BodyError::ProjectIdMismatch))
+    } else if !config.org_id_is_allowed(dsn) {
+        Err(AError::new(HeaderError::InvalidOrganization))
+    } else if !config.allowed_dsns.is_empty() && !config.dsn_is_allowed(dsn) {
+        Err(AError::new(BodyError::DsnNotAllowed))
+    } else if !config.routes.is_empty() {
+        match config.route_for(dsn.public_key(), dsn.project_id().value()) {
+            None => Err(AError::new(BodyError::InvalidProjectId)),
+            Some(route) if !route.upstream.matches(&Host::from_dsn(dsn)) => {
+                Err(AError::new(HeaderError::InvalidHost))
+            }
+            Some(_) => Ok(()),
+        }
+    } else if !config.allowed_dsns.is_empty() {
+        Ok(())
+    } else if !config.project_id_is_allowed(dsn.project_id().value()) {
+        Err(AError::new(BodyError::InvalidProjectId))
+    } else if !dsn_host_is_valid(dsn, &config.remote_hosts) {
+        Err(AError::new(HeaderError::InvalidHost))
+    } else {
+        Ok(())
+    }
+}
 
-    let config = TunnelConfig::borrow_from(state);
-    let hosts = &config.inner.remote_hosts;
-    if config
-        .inner
-        .project_id_is_allowed(sentry_instance.dsn.project_id().value())
-    {
-        if sentry_instance.dsn_host_is_valid(hosts) {
-            match sentry_instance.forward().await {
-                Err(e) => {
-                    error!(
-                        "Failed to forward request to sentry : {} - Host = {}",
-                        e,
-                        sentry_instance.dsn.host()
-                    );
-                    let mime = "text/plain".parse::<Mime>().unwrap();
-                    let res: (StatusCode, Mime, String) =
-                        (StatusCode::INTERNAL_SERVER_ERROR, mime, format!("{}", e));
-                    let res = res.into_response(state);
-                    Ok(res)
-                }
-                Ok(_) => {
-                    let res = create_empty_response(state, StatusCode::OK);
-                    Ok(res)
+/**
+ * SDKs that use the tunnel as their dsn host send envelopes with a dsn pointing to the tunnel
+ * itself. On the ingest routes, such a dsn is moved to the upstream of its route, or to the
+ * sentry instance of the default dsn.
+ */
+fn ingest_dsn(config: &Config, dsn: Dsn, path_project_id: Option<u64>) -> Result<Dsn, AError> {
+    if path_project_id.is_none() {
+        return Ok(dsn);
+    }
+    let public_key = dsn.public_key();
+    let project_id = dsn.project_id().value();
+    match config.route_for(public_key, project_id) {
+        Some(route) if !route.upstream.matches(&Host::from_dsn(&dsn)) => {
+            Ok(route.upstream.dsn(public_key, project_id)?)
+        }
+        Some(_) => Ok(dsn),
+        None => match &config.default_dsn {
+            Some(default_dsn) if !dsn_host_is_valid(&dsn, &config.remote_hosts) => {
+                dsn_with_key_and_project(default_dsn, public_key, project_id)
+            }
+            _ => Ok(dsn),
+        },
+    }
+}
+
+/**
+ * Read the request body until the envelope header line is complete. Returns everything that was
+ * read so far and the length of the header line, the rest of the body is left in `body`.
+ */
+async fn read_envelope_header(body: &mut Body, max_size: u64) -> Result<(Bytes, usize), AError> {
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        let searched = buffer.len();
+        buffer.extend_from_slice(&chunk);
+        if let Some(offset) = buffer[searched..].iter().position(|b| *b == b'\n') {
+            return Ok((buffer.freeze(), searched + offset));
+        }
+        if buffer.len() as u64 > max_size {
+            return Err(AError::new(HeaderError::ContentIsTooBig));
+        }
+    }
+    let header_length = buffer.len();
+    Ok((buffer.freeze(), header_length))
+}
+
+/**
+ * Chain the bytes already read with the rest of the request body, failing as soon as more than
+ * `max_size` bytes went through. `exceeded` is set when that happens.
+ */
+fn limited_body(prefix: Bytes, body: Body, max_size: u64, exceeded: Arc<AtomicBool>) -> AsyncBody {
+    let mut received: u64 = 0;
+    let chunks = stream::once(async move { Ok(prefix) })
+        .chain(body.map_err(io::Error::other))
+        .map(move |chunk: io::Result<Bytes>| {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if received > max_size {
+                exceeded.store(true, Ordering::Relaxed);
+                return Err(io::Error::other(HeaderError::ContentIsTooBig));
+            }
+            Ok(chunk)
+        });
+    AsyncBody::from_reader(chunks.into_async_read())
+}
+
+/**
+ * Build the response sent back to the client once the envelope has been forwarded. The status,
+ * rate limit headers and body of the relay are passed through.
+ */
+fn forward_response(
+    state: &State,
+    result: Result<UpstreamResponse, AError>,
+    dsn: &Dsn,
+) -> Response<Body> {
+    match result {
+        Err(e) if e.is::<CircuitOpen>() => {
+            let circuit_open = e.downcast_ref::<CircuitOpen>().unwrap();
+            warn!("{} - Host = {}", e, dsn.host());
+            let mut response = create_empty_response(state, circuit_open.status_code());
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(circuit_open.retry_after.as_secs() + 1),
+            );
+            response
+        }
+        Err(e) => {
+            error!(
+                "Failed to forward request to sentry : {} - Host = {}",
+                e,
+                dsn.host()
+            );
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::INTERNAL_SERVER_ERROR, mime, format!("{}", e));
+            res.into_response(state)
+        }
+        Ok(upstream) => {
+            if !upstream.status.is_success() {
+                warn!(
+                    "Sentry answered {} - Host = {}",
+                    upstream.status,
+                    dsn.host()
+                );
+            }
+            let mut response = create_empty_response(state, upstream.status);
+            let headers = response.headers_mut();
+            for (name, value) in [
+                (header::RETRY_AFTER, upstream.retry_after),
+                (
+                    HeaderName::from_static("x-sentry-rate-limits"),
+                    upstream.rate_limits,
+                ),
+                (header::CONTENT_TYPE, upstream.content_type),
+            ] {
+                if let Some(value) = value {
+                    headers.insert(name, value);
                 }
             }
-        } else {
-            Err(AError::new(HeaderError::InvalidHost))
+            *response.body_mut() = Body::from(upstream.body);
+            response
         }
-    } else {
-        Err(AError::new(BodyError::InvalidProjectId))
     }
 }
 
-async fn post_tunnel_handler(mut state: State) -> HandlerResult {
-    match tunnel_handler(&mut state).await {
+/**
+ * Answer a request locally with 429 while sentry rate limits its dsn, without calling sentry
+ */
+fn rate_limited_response(state: &State, rate_limits: &RateLimits, dsn: &Dsn) -> Response<Body> {
+    info!(
+        "Rate limited by sentry, envelope dropped - Host = {}",
+        dsn.host()
+    );
+    let mut response = create_empty_response(state, StatusCode::TOO_MANY_REQUESTS);
+    if let Some((retry_after, limits)) = rate_limits.headers_for(dsn) {
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&retry_after) {
+            headers.insert(header::RETRY_AFTER, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&limits) {
+            headers.insert(HeaderName::from_static("x-sentry-rate-limits"), value);
+        }
+    }
+    response
+}
+
+/**
+ * Answer a request with 429 when a local rate limit of the tunnel is exceeded
+ */
+fn throttled_response(state: &State, retry_after: Duration) -> Response<Body> {
+    let seconds = retry_after.as_secs() + 1;
+    info!("Request throttled for {} seconds", seconds);
+    let mut response = create_empty_response(state, StatusCode::TOO_MANY_REQUESTS);
+    let headers = response.headers_mut();
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    if let Ok(value) = HeaderValue::from_str(&format!("{}::key", seconds)) {
+        headers.insert(HeaderName::from_static("x-sentry-rate-limits"), value);
+    }
+    response
+}
+
+/**
+ * Answer a request with 429 when the tunnel is too busy for envelopes of this priority
+ */
+fn shed_response(state: &State, priority: Priority) -> Response<Body> {
+    info!(
+        "Too many envelopes in flight, {} priority envelope shed",
+        priority
+    );
+    let mut response = create_empty_response(state, StatusCode::TOO_MANY_REQUESTS);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(1));
+    response
+}
+
+/**
+ * Check the envelope header as soon as it has been received, then stream the rest of the body to
+ * the relay without keeping it in memory.
+ *
+ * The body can only be sent once and its items are not read : retries, the spool, the forwarding
+ * queue, compression, failover and priorities do not apply to streamed envelopes.
+ */
+async fn streaming_tunnel_handler(
+    state: &mut State,
+    tunnel: &TunnelConfig,
+    fallback_dsn: Option<Dsn>,
+    path_project_id: Option<u64>,
+) -> Result<Response<Body>, AError> {
+    let config = &tunnel.inner;
+    let rate_limits = &tunnel.rate_limits;
+    let mut body = Body::take_from(state);
+    let max_size = config.max_content_size;
+    let (prefix, header_length) = read_envelope_header(&mut body, max_size).await?;
+    let (_, dsn) = parse_envelope_header(&prefix[..header_length])?;
+    let dsn = dsn
+        .or(fallback_dsn)
+        .ok_or(BodyError::MissingDsnKeyInHeader)?;
+    let dsn = ingest_dsn(config, dsn, path_project_id)?;
+    check_dsn(config, &dsn, path_project_id)?;
+    if let Err(retry_after) = tunnel.throttle.check_project(dsn.project_id().value()) {
+        return Ok(throttled_response(state, retry_after));
+    }
+    let (prefix, dsn) = match config.rewrite_for(&dsn) {
+        Some(rewritten) => (
+            rewrite_header_dsn(&prefix, header_length, rewritten)?,
+            rewritten.clone(),
+        ),
+        None => (prefix, dsn),
+    };
+    // Items are not read before forwarding, so only limits on every category can be honored
+    if rate_limits.limited_for(&dsn, "").is_some() {
+        return Ok(rate_limited_response(state, rate_limits, &dsn));
+    }
+    // Nor can their priority be known
+    let _in_flight = match &tunnel.shedder {
+        Some(shedder) => match shedder.try_acquire(Priority::Normal) {
+            Some(in_flight) => Some(in_flight),
+            None => return Ok(shed_response(state, Priority::Normal)),
+        },
+        None => None,
+    };
+
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let body = limited_body(prefix, body, max_size, exceeded.clone());
+    // The body can only be sent once, so there is no failover to another relay
+    let relay = tunnel.upstream.relay_for(config, &dsn, &[]);
+    let breakers = tunnel.upstream.breakers.as_ref();
+    if let Some(breakers) = breakers {
+        if let Err(open) = breakers.allow(&relay.host) {
+            return Ok(forward_response(state, Err(AError::new(open)), &dsn));
+        }
+    }
+    let sent = Instant::now();
+    let result = forward_body(
+        &tunnel.upstream.client,
+        &dsn,
+        &relay.host,
+        body,
+        None,
+        MAX_RESPONSE_SIZE,
+    )
+    .await;
+    let exceeded = exceeded.load(Ordering::Relaxed);
+    // A body that was too big fails the request whatever the health of the relay
+    if let Some(breakers) = breakers.filter(|_| !exceeded) {
+        breakers.record(&relay.host, &result, sent.elapsed());
+    }
+    drop(relay);
+    if exceeded {
+        return Err(AError::new(HeaderError::ContentIsTooBig));
+    }
+    if let Ok(upstream) = &result {
+        rate_limits.update(&dsn, upstream);
+    }
+    Ok(forward_response(state, result, &dsn))
+}
+
+async fn tunnel_handler(
+    state: &mut State,
+    path_project_id: Option<u64>,
+    endpoint: Endpoint,
+) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::take_from(state);
+    let tunnel = TunnelConfig::borrow_from(state).clone();
+    let config = tunnel.inner.clone();
+    let rate_limits = tunnel.rate_limits.clone();
+    let client_ip = client_addr(state).map(|addr| addr.ip().to_string());
+    let origin = headers
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok());
+    if let Err(retry_after) = tunnel.throttle.check_client(client_ip.as_deref(), origin) {
+        return Ok(throttled_response(state, retry_after));
+    }
+    check_content_length(&headers, config.max_content_size)?;
+    let fallback_dsn = fallback_dsn(state, &headers, &config, path_project_id)?;
+
+    if config.stream_bodies
+        && endpoint == Endpoint::Envelope
+        && !headers.contains_key(header::CONTENT_ENCODING)
+    {
+        return streaming_tunnel_handler(state, &tunnel, fallback_dsn, path_project_id).await;
+    }
+
+    let mut full_body = read_body(Body::take_from(state), config.max_content_size).await?;
+    if let Some(content_encoding) = headers.get(header::CONTENT_ENCODING) {
+        let content_encoding = content_encoding
+            .to_str()
+            .map_err(|_| DecodingError::UnsupportedEncoding(format!("{:?}", content_encoding)))?;
+        full_body = decode_body_async(
+            full_body,
+            content_encoding.to_string(),
+            MAX_DECOMPRESSED_SIZE,
+            MAX_COMPRESSION_RATIO,
+        )
+        .await?;
+    }
+    if endpoint == Endpoint::Store {
+        full_body = envelope_from_event(&full_body)?;
+    }
+    let mut sentry_instance = parse_body(full_body, fallback_dsn)?;
+    sentry_instance.dsn = ingest_dsn(&config, sentry_instance.dsn, path_project_id)?;
+    check_dsn(&config, &sentry_instance.dsn, path_project_id)?;
+    if let Err(retry_after) = tunnel
+        .throttle
+        .check_project(sentry_instance.dsn.project_id().value())
+    {
+        return Ok(throttled_response(state, retry_after));
+    }
+    if let Some(rewritten) = config.rewrite_for(&sentry_instance.dsn) {
+        sentry_instance.rewrite_dsn(rewritten.clone())?;
+    }
+    let dsn = sentry_instance.dsn.clone();
+    let had_items = !sentry_instance.items.is_empty();
+    sentry_instance.retain_items(|item| {
+        rate_limits
+            .limited_for(&dsn, item.data_category())
+            .is_none()
+    })?;
+    if had_items && sentry_instance.items.is_empty() {
+        return Ok(rate_limited_response(state, &rate_limits, &dsn));
+    }
+    let priority = envelope_priority(&sentry_instance, &config.category_priorities);
+    let in_flight = match &tunnel.shedder {
+        Some(shedder) => match shedder.try_acquire(priority) {
+            Some(in_flight) => Some(in_flight),
+            None => return Ok(shed_response(state, priority)),
+        },
+        None => None,
+    };
+
+    if let Some(queue) = &tunnel.queue {
+        let response = accepted_response(state, &sentry_instance);
+        // The envelope stays in flight until a worker has delivered it
+        queue.push(sentry_instance, priority, in_flight)?;
+        return Ok(response);
+    }
+    let delivery = deliver(
+        &config,
+        &tunnel.upstream,
+        &rate_limits,
+        tunnel.spool.as_ref(),
+        &sentry_instance,
+    )
+    .await?;
+    match delivery {
+        Delivery::Forwarded(result) => Ok(forward_response(state, result, &sentry_instance.dsn)),
+        Delivery::Spooled => Ok(create_empty_response(state, StatusCode::OK)),
+    }
+}
+
+/**
+ * What became of an envelope handed to `deliver`
+ */
+enum Delivery {
+    Forwarded(Result<UpstreamResponse, AError>),
+    Spooled,
+}
+
+/**
+ * Forward an envelope to its relay, or store it in the spool when there is one and the relay is
+ * not available
+ */
+async fn deliver(
+    config: &Config,
+    upstream: &Upstream,
+    rate_limits: &RateLimits,
+    spool: Option<&Arc<Spool>>,
+    envelope: &SentryEnvelope,
+) -> Result<Delivery, SpoolError> {
+    if let Some(spool) = spool {
+        // Envelopes stay in order : once one has been spooled, the next ones go after it
+        if !blocking(spool, |spool| Ok(spool.is_empty())).await? {
+            return spool_envelope(spool, envelope).await;
+        }
+    }
+
+    let result = envelope.forward(config, upstream).await;
+    if let Ok(upstream) = &result {
+        rate_limits.update(&envelope.dsn, upstream);
+    }
+    match spool {
+        Some(spool) if config.retry.is_retryable(&result) => spool_envelope(spool, envelope).await,
+        _ => Ok(Delivery::Forwarded(result)),
+    }
+}
+
+/**
+ * Store an envelope in the spool, to be forwarded once the relay is available again
+ */
+async fn spool_envelope(
+    spool: &Arc<Spool>,
+    envelope: &SentryEnvelope,
+) -> Result<Delivery, SpoolError> {
+    let dsn = dsn_header_value(&envelope.dsn);
+    let body = envelope.raw_body.clone();
+    blocking(spool, move |spool| spool.push(&dsn, &body)).await?;
+    info!("Envelope spooled - Host = {}", envelope.dsn.host());
+    Ok(Delivery::Spooled)
+}
+
+/**
+ * Run an operation on the spool in the blocking thread pool. The spool writes and syncs files
+ * while holding its lock, which must not hold up the workers of the runtime.
+ */
+async fn blocking<T, F>(spool: &Arc<Spool>, operation: F) -> Result<T, SpoolError>
+where
+    F: FnOnce(&Spool) -> Result<T, SpoolError> + Send + 'static,
+    T: Send + 'static,
+{
+    let spool = spool.clone();
+    tokio::task::spawn_blocking(move || operation(&spool))
+        .await
+        .map_err(|e| SpoolError::Io(io::Error::other(e)))?
+}
+
+/**
+ * Forward an envelope taken from the asynchronous queue, whose client has already been answered
+ */
+async fn deliver_queued(
+    config: Arc<Config>,
+    upstream: Arc<Upstream>,
+    rate_limits: Arc<RateLimits>,
+    spool: Option<Arc<Spool>>,
+    envelope: SentryEnvelope,
+) {
+    let delivery = deliver(&config, &upstream, &rate_limits, spool.as_ref(), &envelope).await;
+    match delivery {
+        Ok(Delivery::Forwarded(Ok(upstream))) if !upstream.status.is_success() => warn!(
+            "Sentry answered {} to a queued envelope - Host = {}",
+            upstream.status,
+            envelope.dsn.host()
+        ),
+        Ok(Delivery::Forwarded(Err(e))) => error!(
+            "Failed to forward a queued envelope to sentry : {} - Host = {}",
+            e,
+            envelope.dsn.host()
+        ),
+        Err(e) => error!("{}", e),
+        Ok(_) => {}
+    }
+}
+
+/**
+ * The answer sent right away when envelopes are forwarded asynchronously, which is the answer of
+ * sentry to a valid envelope : the id of its event, when it has one
+ */
+fn accepted_response(state: &State, envelope: &SentryEnvelope) -> Response<Body> {
+    let body = match envelope.header.get("event_id") {
+        Some(event_id) => json!({ "id": event_id }),
+        None => json!({}),
+    };
+    create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        body.to_string(),
+    )
+}
+
+async fn handle_request(
+    mut state: State,
+    path_project_id: Option<u64>,
+    endpoint: Endpoint,
+) -> HandlerResult {
+    match tunnel_handler(&mut state, path_project_id, endpoint).await {
         Ok(val) => Ok((state, val)),
         Err(error) => {
+            let status = if let Some(header_error) = error.downcast_ref::<HeaderError>() {
+                header_error.status_code()
+            } else if let Some(decoding_error) = error.downcast_ref::<DecodingError>() {
+                decoding_error.status_code()
+            } else if let Some(spool_error) = error.downcast_ref::<SpoolError>() {
+                spool_error.status_code()
+            } else if let Some(queue_error) = error.downcast_ref::<QueueError>() {
+                queue_error.status_code()
+            } else {
+                StatusCode::BAD_REQUEST
+            };
             let mime = "text/plain".parse::<Mime>().unwrap();
-            let res: (StatusCode, Mime, String) = (
-                StatusCode::BAD_REQUEST,
-                mime,
-                format!("{}", error),
-            );
+            let res: (StatusCode, Mime, String) = (status, mime, format!("{}", error));
             let response = res.into_response(&state);
             Ok((state, response))
         }
     }
 }
 
+async fn post_tunnel_handler(state: State) -> HandlerResult {
+    handle_request(state, None, Endpoint::Envelope).await
+}
+
+async fn post_envelope_handler(mut state: State) -> HandlerResult {
+    let path = ProjectPath::take_from(&mut state);
+    handle_request(state, Some(path.project_id), Endpoint::Envelope).await
+}
+
+async fn post_store_handler(mut state: State) -> HandlerResult {
+    let path = ProjectPath::take_from(&mut state);
+    handle_request(state, Some(path.project_id), Endpoint::Store).await
+}
+
+/**
+ * Returns the state of the local rate limits, of the spool and of the forwarding queue of the
+ * tunnel, as JSON
+ */
+async fn stats_handler(state: State) -> HandlerResult {
+    let tunnel = TunnelConfig::borrow_from(&state);
+    let stats = json!({
+        "rate_limits": tunnel.throttle.stats(),
+        "spool": tunnel.spool.as_ref().map(|spool| spool.stats()),
+        "queue": tunnel.queue.as_ref().map(|queue| queue.stats()),
+        "load": tunnel.shedder.as_ref().map(|shedder| shedder.stats()),
+        "circuit_breakers": tunnel.upstream.breakers.as_ref().map(|breakers| breakers.stats()),
+        "relays": tunnel.upstream.pool.as_ref().map(|pool| pool.stats()),
+    });
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(stats.to_string()))
+        .unwrap();
+    Ok((state, response))
+}
 
 async fn health_handler(state: State) -> HandlerResult {
     let response = Response::builder()
@@ -164,15 +791,77 @@ async fn health_handler(state: State) -> HandlerResult {
     Ok((state, response))
 }
 
-pub fn router(path: &str, config: Config) -> Router {
+/**
+ * Build the router of the tunnel, and start the spool drainer and the forwarding queue when they
+ * are configured. Fails when the http client, the spool or the queue can not be set up.
+ */
+pub fn router(path: &str, config: Config) -> Result<Router, String> {
+    let throttle = Throttle::new(
+        config.ip_rate_limit,
+        config.project_rate_limit,
+        config.origin_rate_limit,
+    );
+    let config = Arc::new(config);
+    let upstream = Upstream::new(&config)
+        .map(Arc::new)
+        .map_err(|e| format!("Failed to build the http client : {}", e))?;
+    let spool = match config.spool.clone() {
+        Some(spool_config) => {
+            let dir = spool_config.dir.display().to_string();
+            let spool = Spool::open(spool_config)
+                .map(Arc::new)
+                .map_err(|e| format!("Failed to open the spool in {} : {}", dir, e))?;
+            start_drainer(spool.clone(), config.clone(), upstream.clone())
+                .map_err(|e| format!("Failed to start the spool drainer : {}", e))?;
+            Some(spool)
+        }
+        None => None,
+    };
+    let rate_limits = Arc::new(RateLimits::default());
+    let queue = match config.queue {
+        Some(queue_config) => {
+            let (config, upstream) = (config.clone(), upstream.clone());
+            let (rate_limits, spool) = (rate_limits.clone(), spool.clone());
+            let queue = ForwardQueue::start(queue_config, move |envelope| {
+                deliver_queued(
+                    config.clone(),
+                    upstream.clone(),
+                    rate_limits.clone(),
+                    spool.clone(),
+                    envelope,
+                )
+            })
+            .map_err(|e| format!("Failed to start the forwarding queue : {}", e))?;
+            Some(Arc::new(queue))
+        }
+        None => None,
+    };
+    let shedder = config
+        .max_in_flight
+        .map(|budget| Arc::new(LoadShedder::new(budget)));
     let middleware = StateMiddleware::new(TunnelConfig {
-        inner: Arc::new(config),
+        inner: config,
+        rate_limits,
+        throttle: Arc::new(throttle),
+        spool,
+        queue,
+        shedder,
+        upstream,
     });
     let pipeline = single_middleware(middleware);
     let (chain, pipelines) = single_pipeline(pipeline);
 
-    build_router(chain, pipelines, |route| {
+    Ok(build_router(chain, pipelines, |route| {
         route.post(path).to_async(post_tunnel_handler);
+        route
+            .post("/api/:project_id/envelope")
+            .with_path_extractor::<ProjectPath>()
+            .to_async(post_envelope_handler);
+        route
+            .post("/api/:project_id/store")
+            .with_path_extractor::<ProjectPath>()
+            .to_async(post_store_handler);
         route.get("/healthz").to_async(health_handler);
-    })
+        route.get("/stats").to_async(stats_handler);
+    }))
 }
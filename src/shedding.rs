@@ -0,0 +1,170 @@
+use crate::envelope::SentryEnvelope;
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/**
+ * How important the items of a data category are. Lower priorities are shed first when the
+ * tunnel is overloaded.
+ */
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /**
+     * The part of a budget that envelopes of this priority may use : low priority envelopes are
+     * refused once half of it is used, normal ones once 80% of it is used. Shares are rounded
+     * down, so that a small budget is kept for higher priorities, but a normal envelope always
+     * gets a slot of a non-empty budget.
+     */
+    pub fn limit(&self, budget: usize) -> usize {
+        let share = |share: f64| (budget as f64 * share).floor() as usize;
+        match self {
+            Priority::Low => share(0.5),
+            Priority::Normal => share(0.8).max(1).min(budget),
+            Priority::High => budget,
+        }
+    }
+
+    /**
+     * The priority of the items of a data category when it is not configured : errors, crash
+     * reports and user feedback are high, replays, profiles and unknown items are low
+     */
+    pub fn of_category(category: &str) -> Priority {
+        match category {
+            "error" | "default" | "user_report" | "internal" => Priority::High,
+            "replay" | "profile" | "unknown" => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            other => Err(format!(
+                "{} is not a valid priority, use low, normal or high",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+/**
+ * The priority of an envelope, which is the highest priority of its items, so that an error sent
+ * with its attachments or replay is never shed before the error alone would be.
+ *
+ * `priorities` overrides the priority of some data categories. Envelopes without items are normal.
+ */
+pub fn envelope_priority(
+    envelope: &SentryEnvelope,
+    priorities: &HashMap<String, Priority>,
+) -> Priority {
+    envelope
+        .items
+        .iter()
+        .map(|item| {
+            let category = item.data_category();
+            priorities
+                .get(category)
+                .copied()
+                .unwrap_or_else(|| Priority::of_category(category))
+        })
+        .max()
+        .unwrap_or(Priority::Normal)
+}
+
+/**
+ * Limits the number of envelopes being forwarded at the same time. Each priority may only use its
+ * share of the budget, so that lower priorities are refused first.
+ */
+#[derive(Debug)]
+pub struct LoadShedder {
+    budget: usize,
+    in_flight: Arc<AtomicUsize>,
+    shed: [AtomicU64; 3],
+}
+
+/**
+ * A slot of the in-flight budget, given back when dropped. It is moved along with an envelope
+ * handed to the forwarding queue, so that the envelope counts as in flight until it is delivered.
+ */
+#[derive(Debug)]
+pub struct InFlight {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(budget: usize) -> LoadShedder {
+        LoadShedder {
+            budget,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            shed: Default::default(),
+        }
+    }
+
+    /**
+     * Take a slot of the budget for an envelope of this priority. Returns None, and counts the
+     * envelope as shed, when its priority has used up its share.
+     */
+    pub fn try_acquire(&self, priority: Priority) -> Option<InFlight> {
+        let limit = priority.limit(self.budget);
+        match self
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < limit).then_some(in_flight + 1)
+            }) {
+            Ok(_) => Some(InFlight {
+                in_flight: self.in_flight.clone(),
+            }),
+            Err(_) => {
+                self.shed[priority as usize].fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /**
+     * The budget, the envelopes in flight and the number of envelopes shed per priority, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        json!({
+            "budget": self.budget,
+            "in_flight": self.in_flight.load(Ordering::Relaxed),
+            "shed": {
+                "low": self.shed[Priority::Low as usize].load(Ordering::Relaxed),
+                "normal": self.shed[Priority::Normal as usize].load(Ordering::Relaxed),
+                "high": self.shed[Priority::High as usize].load(Ordering::Relaxed),
+            },
+        })
+    }
+}
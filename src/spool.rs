@@ -0,0 +1,565 @@
+use crate::config::Config;
+use crate::envelope::forward_raw;
+use crate::upstream::Upstream;
+use bytes::Bytes;
+use gotham::hyper::StatusCode;
+use sentry_types::Dsn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use log::*;
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEGMENT_EXTENSION: &str = "spool";
+const CURSOR_FILE: &str = "cursor";
+/**
+ * How long the drainer waits for new envelopes before looking at the spool again
+ */
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * What to do when the spool is full
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Delete the oldest segment to make room for new envelopes
+    DropOldest,
+    /// Refuse new envelopes until the spool is drained
+    DropNewest,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "oldest" => Ok(EvictionPolicy::DropOldest),
+            "newest" => Ok(EvictionPolicy::DropNewest),
+            other => Err(format!(
+                "{} is not a valid spool eviction policy, use oldest or newest",
+                other
+            )),
+        }
+    }
+}
+
+/**
+ * Where and how much envelopes are spooled when the relay can not take them
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// Maximum size of all the segment files, in bytes
+    pub max_size: u64,
+    /// Envelopes older than this are dropped instead of being forwarded
+    pub max_age: Duration,
+    /// A new segment file is started once the current one is bigger than this, in bytes
+    pub segment_size: u64,
+    pub eviction: EvictionPolicy,
+}
+
+impl SpoolConfig {
+    pub fn new(dir: PathBuf) -> SpoolConfig {
+        SpoolConfig {
+            dir,
+            max_size: 100_000_000,
+            max_age: Duration::from_secs(24 * 3600),
+            segment_size: 1_000_000,
+            eviction: EvictionPolicy::DropOldest,
+        }
+    }
+}
+
+impl Display for SpoolConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} (max {} bytes, max age {:?}, drop {} when full)",
+            self.dir.display(),
+            self.max_size,
+            self.max_age,
+            match self.eviction {
+                EvictionPolicy::DropOldest => "oldest",
+                EvictionPolicy::DropNewest => "newest",
+            }
+        ))
+    }
+}
+
+/**
+ * An error raised while spooling an envelope
+ */
+#[derive(Debug)]
+pub enum SpoolError {
+    SpoolIsFull,
+    Io(io::Error),
+}
+
+impl Display for SpoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpoolError::SpoolIsFull => f.write_str("The spool is full."),
+            SpoolError::Io(e) => f.write_fmt(format_args!("Failed to write to the spool : {}", e)),
+        }
+    }
+}
+
+impl Error for SpoolError {}
+
+impl SpoolError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            SpoolError::SpoolIsFull => StatusCode::SERVICE_UNAVAILABLE,
+            SpoolError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<io::Error> for SpoolError {
+    fn from(e: io::Error) -> Self {
+        SpoolError::Io(e)
+    }
+}
+
+/**
+ * The line written before each envelope body in a segment file
+ */
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordHeader {
+    dsn: String,
+    received: u64,
+    length: u64,
+}
+
+/**
+ * An envelope read back from the spool
+ */
+#[derive(Debug)]
+pub struct SpooledEnvelope {
+    pub dsn: Dsn,
+    pub body: Bytes,
+    position: Position,
+}
+
+/**
+ * The position of the next record to deliver
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+struct SpoolState {
+    /// Oldest first, envelopes are appended to the last one
+    segments: VecDeque<Segment>,
+    /// Offset of the next record to deliver in the first segment
+    cursor: u64,
+    /// The segment that envelopes are appended to, None until one has been written in this run
+    writing: Option<u64>,
+    next_id: u64,
+    evicted: u64,
+    expired: u64,
+}
+
+impl SpoolState {
+    fn total_size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.segments.len() {
+            0 => true,
+            1 => self.cursor >= self.segments[0].size,
+            _ => false,
+        }
+    }
+}
+
+/**
+ * An on-disk queue of envelopes, stored in append-only segment files. Envelopes are delivered
+ * in order by a background drainer, and the position of the next one is saved in a cursor file so
+ * that the spool survives restarts.
+ *
+ * Its methods read and write files while holding a lock : request handlers call them from the
+ * blocking thread pool, and the drainer runs on a thread of its own.
+ */
+#[derive(Debug)]
+pub struct Spool {
+    config: SpoolConfig,
+    state: Mutex<SpoolState>,
+    notify: Notify,
+}
+
+impl Spool {
+    /**
+     * Open the spool directory, creating it when needed, and pick up the segments left by a
+     * previous run
+     */
+    pub fn open(config: SpoolConfig) -> io::Result<Spool> {
+        fs::create_dir_all(&config.dir)?;
+        let mut ids = vec![];
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION) {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        let mut segments = VecDeque::new();
+        for id in ids {
+            let size = fs::metadata(segment_path(&config.dir, id))?.len();
+            segments.push_back(Segment { id, size });
+        }
+
+        let mut cursor = 0;
+        if let Ok(saved) = fs::read_to_string(config.dir.join(CURSOR_FILE)) {
+            if let Some((segment, offset)) = saved.trim().split_once(' ') {
+                if let (Ok(segment), Ok(offset)) = (segment.parse::<u64>(), offset.parse()) {
+                    while segments.front().is_some_and(|s| s.id < segment) {
+                        let old = segments.pop_front().unwrap();
+                        remove_segment(&config.dir, old.id);
+                    }
+                    if segments.front().is_some_and(|s| s.id == segment) {
+                        cursor = offset;
+                    }
+                }
+            }
+        }
+        let next_id = segments.back().map_or(0, |segment| segment.id + 1);
+        if !segments.is_empty() {
+            info!("Spool : {} segments left by a previous run", segments.len());
+        }
+        Ok(Spool {
+            config,
+            state: Mutex::new(SpoolState {
+                segments,
+                cursor,
+                writing: None,
+                next_id,
+                evicted: 0,
+                expired: 0,
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    /**
+     * Returns true when every spooled envelope has been delivered
+     */
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().is_empty()
+    }
+
+    /**
+     * Append an envelope to the spool. When the spool is full, the eviction policy either deletes
+     * the oldest segments or refuses the envelope.
+     */
+    pub fn push(&self, dsn: &str, body: &[u8]) -> Result<(), SpoolError> {
+        let header = RecordHeader {
+            dsn: dsn.to_string(),
+            received: unix_time(),
+            length: body.len() as u64,
+        };
+        let mut record = serde_json::to_vec(&header).map_err(io::Error::from)?;
+        record.push(b'\n');
+        record.extend_from_slice(body);
+        record.push(b'\n');
+        let length = record.len() as u64;
+        if length > self.config.max_size {
+            return Err(SpoolError::SpoolIsFull);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        while state.total_size() + length > self.config.max_size {
+            if self.config.eviction == EvictionPolicy::DropNewest {
+                return Err(SpoolError::SpoolIsFull);
+            }
+            let oldest = state.segments.pop_front().unwrap();
+            warn!("Spool is full, dropping segment {}", oldest.id);
+            remove_segment(&self.config.dir, oldest.id);
+            state.cursor = 0;
+            state.evicted += 1;
+            if state.writing == Some(oldest.id) {
+                state.writing = None;
+            }
+        }
+
+        let current = match (state.writing, state.segments.back()) {
+            (Some(id), Some(last)) if last.id == id && last.size < self.config.segment_size => id,
+            _ => {
+                // Segments of a previous run are never appended to, they may end with a partial
+                // record
+                let id = state.next_id;
+                state.next_id += 1;
+                state.segments.push_back(Segment { id, size: 0 });
+                state.writing = Some(id);
+                id
+            }
+        };
+        let path = segment_path(&self.config.dir, current);
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| {
+                file.write_all(&record)?;
+                file.sync_data()
+            });
+        let last = state.segments.back_mut().unwrap();
+        if let Err(e) = written {
+            // Whatever was written is skipped as a partial record, and the next envelope starts a
+            // new segment
+            last.size = fs::metadata(&path).map_or(last.size, |metadata| metadata.len());
+            state.writing = None;
+            return Err(e.into());
+        }
+        last.size += length;
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /**
+     * Read the next envelope to deliver, without removing it from the spool. Fully delivered
+     * segments are deleted, and envelopes older than the maximum age are skipped.
+     */
+    pub fn peek(&self) -> io::Result<Option<SpooledEnvelope>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (id, size) = match state.segments.front() {
+                Some(segment) => (segment.id, segment.size),
+                None => return Ok(None),
+            };
+            if state.cursor >= size {
+                if state.writing == Some(id) {
+                    return Ok(None);
+                }
+                state.segments.pop_front();
+                state.cursor = 0;
+                remove_segment(&self.config.dir, id);
+                continue;
+            }
+
+            let position = Position {
+                segment: id,
+                offset: state.cursor,
+            };
+            let (header, body, next_offset) = match read_record(&self.config.dir, position, size)? {
+                Some(record) => record,
+                None => {
+                    warn!("Spool segment {} ends with a partial record", id);
+                    state.cursor = size;
+                    continue;
+                }
+            };
+            let age = unix_time().saturating_sub(header.received);
+            if age > self.config.max_age.as_secs() {
+                warn!("Dropping a spooled envelope received {} seconds ago", age);
+                state.expired += 1;
+                state.cursor = next_offset;
+                self.save_cursor(&state);
+                continue;
+            }
+            match Dsn::from_str(&header.dsn) {
+                Ok(dsn) => {
+                    // The cursor only moves once the envelope has been delivered
+                    return Ok(Some(SpooledEnvelope {
+                        dsn,
+                        body,
+                        position: Position {
+                            segment: id,
+                            offset: next_offset,
+                        },
+                    }));
+                }
+                Err(e) => {
+                    error!("Dropping a spooled envelope with an invalid dsn : {}", e);
+                    state.cursor = next_offset;
+                    self.save_cursor(&state);
+                }
+            }
+        }
+    }
+
+    /**
+     * Remove a delivered envelope from the spool
+     */
+    pub fn ack(&self, envelope: &SpooledEnvelope) {
+        let mut state = self.state.lock().unwrap();
+        if state.segments.front().map(|segment| segment.id) == Some(envelope.position.segment) {
+            state.cursor = envelope.position.offset;
+            self.save_cursor(&state);
+        }
+    }
+
+    /**
+     * The size and counters of the spool, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "segments": state.segments.len(),
+            "size": state.total_size(),
+            "max_size": self.config.max_size,
+            "pending": !state.is_empty(),
+            "evicted_segments": state.evicted,
+            "expired_envelopes": state.expired,
+        })
+    }
+
+    fn save_cursor(&self, state: &SpoolState) {
+        let segment = match state.segments.front() {
+            Some(segment) => segment.id,
+            None => return,
+        };
+        let path = self.config.dir.join(CURSOR_FILE);
+        let temporary = path.with_extension("tmp");
+        let result = fs::write(&temporary, format!("{} {}", segment, state.cursor))
+            .and_then(|_| fs::rename(&temporary, &path));
+        if let Err(e) = result {
+            error!("Failed to save the spool cursor : {}", e);
+        }
+    }
+}
+
+/**
+ * Forward the spooled envelopes in order, for as long as the process runs. An envelope is removed
+ * from the spool once the relay accepted it, or refused it with a 4xx other than a rate limit.
+ * Anything else, errors included, keeps it in the spool until it expires.
+ */
+pub async fn drain(spool: Arc<Spool>, config: Arc<Config>, upstream: Arc<Upstream>) {
+    let mut failures = 0;
+    loop {
+        let envelope = match spool.peek() {
+            Ok(Some(envelope)) => envelope,
+            Ok(None) => {
+                let _ = tokio::time::timeout(DRAIN_POLL_INTERVAL, spool.notify.notified()).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to read the spool : {}", e);
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        let result = forward_raw(&config, &upstream, &envelope.dsn, &envelope.body).await;
+        let failure = match &result {
+            Ok(response) if response.status.is_success() => None,
+            Ok(response)
+                if response.status.is_client_error()
+                    && response.status != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                warn!(
+                    "Dropping a spooled envelope refused by {} with {}",
+                    envelope.dsn.host(),
+                    response.status
+                );
+                None
+            }
+            Ok(response) => Some(response.status.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        match failure {
+            None => {
+                failures = 0;
+                spool.ack(&envelope);
+            }
+            Some(failure) => {
+                failures += 1;
+                let backoff = config.retry.backoff(failures).max(DRAIN_POLL_INTERVAL);
+                warn!(
+                    "Failed to forward a spooled envelope to {} ({}), retrying in {:?}",
+                    envelope.dsn.host(),
+                    failure,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/**
+ * Start draining the spool on a dedicated thread
+ */
+pub fn start_drainer(
+    spool: Arc<Spool>,
+    config: Arc<Config>,
+    upstream: Arc<Upstream>,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("spool-drainer".to_string())
+        .spawn(move || runtime.block_on(drain(spool, config, upstream)))?;
+    Ok(())
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn remove_segment(dir: &Path, id: u64) {
+    if let Err(e) = fs::remove_file(segment_path(dir, id)) {
+        error!("Failed to remove spool segment {} : {}", id, e);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/**
+ * Read the record at this position of a segment of `size` bytes. Returns None when the segment
+ * ends with a partial record.
+ */
+fn read_record(
+    dir: &Path,
+    position: Position,
+    size: u64,
+) -> io::Result<Option<(RecordHeader, Bytes, u64)>> {
+    let mut file = File::open(segment_path(dir, position.segment))?;
+    file.seek(SeekFrom::Start(position.offset))?;
+    let mut reader = BufReader::new(file);
+    let mut header_line = vec![];
+    reader.read_until(b'\n', &mut header_line)?;
+    let header: RecordHeader = match serde_json::from_slice(&header_line) {
+        Ok(header) => header,
+        Err(_) => return Ok(None),
+    };
+    let next_offset = position.offset + header_line.len() as u64 + header.length + 1;
+    if next_offset > size {
+        return Ok(None);
+    }
+    let mut body = vec![0; header.length as usize + 1];
+    if reader.read_exact(&mut body).is_err() || body.pop() != Some(b'\n') {
+        return Ok(None);
+    }
+    Ok(Some((header, Bytes::from(body), next_offset)))
+}
@@ -0,0 +1,256 @@
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * The maximum number of keys a limiter tracks. The least recently used bucket is forgotten to make
+ * room for a new key.
+ */
+pub const MAX_TRACKED_KEYS: usize = 10_000;
+
+/**
+ * The size and refill rate of a token bucket. Each request takes one token.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketConfig {
+    /// Tokens added per second
+    pub rate: f64,
+    /// Maximum number of tokens, which is the size of the bursts that are allowed
+    pub burst: f64,
+}
+
+impl FromStr for BucketConfig {
+    type Err = String;
+
+    /**
+     * Parse a bucket written as `<requests per second>` or `<requests per second>:<burst>`. The
+     * burst is the rate when it is omitted.
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a valid rate limit, use <rate>[:<burst>]", s);
+        let (rate, burst) = match s.trim().split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s.trim(), None),
+        };
+        let rate = f64::from_str(rate.trim()).map_err(|_| invalid())?;
+        let burst = match burst {
+            Some(burst) => f64::from_str(burst.trim()).map_err(|_| invalid())?,
+            None => rate.max(1.0),
+        };
+        if !rate.is_finite() || rate <= 0.0 || !burst.is_finite() || burst < 1.0 {
+            return Err(invalid());
+        }
+        Ok(BucketConfig { rate, burst })
+    }
+}
+
+impl Display for BucketConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}/s, burst of {}", self.rate, self.burst))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position of the bucket in the order of use of its limiter
+    used: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst);
+        self.updated = now;
+    }
+
+    /**
+     * Returns how long to wait before a token is available, or None when there is one
+     */
+    fn wait(&self, config: &BucketConfig) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / config.rate))
+    }
+}
+
+/**
+ * The buckets of a limiter, and the order in which they were last used
+ */
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    /// The key of each bucket, by position in the order of use
+    recent: BTreeMap<u64, String>,
+    next: u64,
+}
+
+/**
+ * A set of token buckets sharing the same config, one for each key (a client ip, a project id...)
+ */
+#[derive(Debug)]
+pub struct Limiter {
+    config: BucketConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    pub fn new(config: BucketConfig) -> Limiter {
+        Limiter {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /**
+     * Returns the refilled bucket of this key, creating it when needed. The least recently used
+     * bucket is forgotten when a new key would exceed `MAX_TRACKED_KEYS`.
+     */
+    fn bucket<'a>(&self, buckets: &'a mut Buckets, key: &str, now: Instant) -> &'a mut TokenBucket {
+        let used = buckets.next;
+        buckets.next += 1;
+        match buckets.buckets.get_mut(key) {
+            Some(bucket) => {
+                buckets.recent.remove(&bucket.used);
+                bucket.used = used;
+            }
+            None => {
+                if buckets.buckets.len() >= MAX_TRACKED_KEYS {
+                    if let Some((_, oldest)) = buckets.recent.pop_first() {
+                        buckets.buckets.remove(&oldest);
+                    }
+                }
+                buckets.buckets.insert(
+                    key.to_string(),
+                    TokenBucket {
+                        tokens: self.config.burst,
+                        updated: now,
+                        used,
+                    },
+                );
+            }
+        }
+        buckets.recent.insert(used, key.to_string());
+        let bucket = buckets.buckets.get_mut(key).unwrap();
+        bucket.refill(&self.config, now);
+        bucket
+    }
+
+    /**
+     * Take a token from the bucket of this key. Returns how long to wait before a token is
+     * available when the bucket is empty.
+     */
+    pub fn take(&self, key: &str) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, key, Instant::now());
+        match bucket.wait(&self.config) {
+            Some(wait) => Err(wait),
+            None => {
+                bucket.tokens -= 1.0;
+                Ok(())
+            }
+        }
+    }
+
+    /**
+     * The number of keys with a bucket and of keys whose bucket is empty, as JSON. The keys
+     * themselves, such as client ips, are not shown.
+     */
+    pub fn stats(&self) -> Value {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut empty = 0;
+        for bucket in buckets.buckets.values_mut() {
+            bucket.refill(&self.config, now);
+            if bucket.wait(&self.config).is_some() {
+                empty += 1;
+            }
+        }
+        json!({
+            "rate": self.config.rate,
+            "burst": self.config.burst,
+            "keys": buckets.buckets.len(),
+            "empty": empty,
+        })
+    }
+}
+
+/**
+ * The local rate limits of the tunnel, keyed by client ip, project id and `Origin` header. Each
+ * of them is optional.
+ */
+#[derive(Debug, Default)]
+pub struct Throttle {
+    pub ip: Option<Limiter>,
+    pub project: Option<Limiter>,
+    pub origin: Option<Limiter>,
+}
+
+impl Throttle {
+    pub fn new(
+        ip: Option<BucketConfig>,
+        project: Option<BucketConfig>,
+        origin: Option<BucketConfig>,
+    ) -> Throttle {
+        Throttle {
+            ip: ip.map(Limiter::new),
+            project: project.map(Limiter::new),
+            origin: origin.map(Limiter::new),
+        }
+    }
+
+    /**
+     * Take a token for this client ip and origin. No token is taken unless both buckets have one,
+     * so that a request refused by one limit does not count against the other.
+     */
+    pub fn check_client(&self, ip: Option<&str>, origin: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        // The ip buckets are always locked before the origin buckets
+        let mut locked = [(&self.ip, ip), (&self.origin, origin)]
+            .iter()
+            .filter_map(|(limiter, key)| {
+                let limiter = limiter.as_ref()?;
+                Some((limiter, (*key)?, limiter.buckets.lock().unwrap()))
+            })
+            .collect::<Vec<_>>();
+        let mut buckets = locked
+            .iter_mut()
+            .map(|(limiter, key, buckets)| (limiter.config, limiter.bucket(buckets, key, now)))
+            .collect::<Vec<_>>();
+        if let Some(wait) = buckets
+            .iter()
+            .filter_map(|(config, bucket)| bucket.wait(config))
+            .max()
+        {
+            return Err(wait);
+        }
+        for (_, bucket) in buckets.iter_mut() {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /**
+     * Take a token for this project
+     */
+    pub fn check_project(&self, project_id: u64) -> Result<(), Duration> {
+        match &self.project {
+            Some(limiter) => limiter.take(&project_id.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * The state of every limit, as JSON
+     */
+    pub fn stats(&self) -> Value {
+        json!({
+            "ip": self.ip.as_ref().map(Limiter::stats),
+            "project": self.project.as_ref().map(Limiter::stats),
+            "origin": self.origin.as_ref().map(Limiter::stats),
+        })
+    }
+}
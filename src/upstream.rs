@@ -0,0 +1,87 @@
+use crate::breaker::CircuitBreakers;
+use crate::config::{Config, Host};
+use crate::pool::{Outstanding, RelayPool};
+use isahc::HttpClient;
+use sentry_types::Dsn;
+
+use std::panic::AssertUnwindSafe;
+
+/**
+ * The state shared by every request forwarded to the sentry relays : the HTTP client and its
+ * connections, the circuit breakers, and the pool of relays envelopes are spread over
+ */
+#[derive(Debug)]
+pub struct Upstream {
+    // Gotham requires shared state to be unwind safe. A panic can not leave the client, which
+    // only holds connections, in an inconsistent state.
+    pub client: AssertUnwindSafe<HttpClient>,
+    pub breakers: Option<CircuitBreakers>,
+    pub pool: Option<RelayPool>,
+}
+
+/**
+ * The relay picked for a request
+ */
+#[derive(Debug)]
+pub struct Relay<'a> {
+    pub host: Host,
+    _outstanding: Option<Outstanding<'a>>,
+}
+
+impl Upstream {
+    pub fn new(config: &Config) -> Result<Upstream, isahc::Error> {
+        Ok(Upstream {
+            client: AssertUnwindSafe(config.client.build()?),
+            breakers: config.breaker.map(CircuitBreakers::new),
+            pool: if config.relays.is_empty() {
+                None
+            } else {
+                Some(RelayPool::new(config.relays.clone(), config.balancing))
+            },
+        })
+    }
+
+    /**
+     * Returns the pool that envelopes for this dsn are spread over. Routes with a target are
+     * always sent to that target.
+     */
+    fn pool_for(&self, config: &Config, dsn: &Dsn) -> Option<&RelayPool> {
+        let route = config.route_for(dsn.public_key(), dsn.project_id().value());
+        match route {
+            Some(route) if route.target.is_some() => None,
+            _ => self.pool.as_ref(),
+        }
+    }
+
+    /**
+     * Pick the relay that an envelope for this dsn is sent to, avoiding the relays of the pool
+     * that were already `tried` and those whose circuit breaker is open
+     */
+    pub fn relay_for(&self, config: &Config, dsn: &Dsn, tried: &[Host]) -> Relay<'_> {
+        let healthy = |host: &Host| match &self.breakers {
+            Some(breakers) => !breakers.is_open(host),
+            None => true,
+        };
+        match self
+            .pool_for(config, dsn)
+            .and_then(|pool| pool.pick(healthy, tried))
+        {
+            Some(outstanding) => Relay {
+                host: outstanding.host().clone(),
+                _outstanding: Some(outstanding),
+            },
+            None => Relay {
+                host: config.target_for(dsn),
+                _outstanding: None,
+            },
+        }
+    }
+
+    /**
+     * Returns true when the pool of this dsn has a relay that was not `tried` yet
+     */
+    pub fn can_fail_over(&self, config: &Config, dsn: &Dsn, tried: &[Host]) -> bool {
+        self.pool_for(config, dsn)
+            .is_some_and(|pool| pool.relays().iter().any(|relay| !tried.contains(relay)))
+    }
+}
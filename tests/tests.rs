@@ -1,14 +1,30 @@
 #[cfg(test)]
 mod tests {
-    use sentry_tunnel::config::Host;
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures_util::stream;
     use gotham::hyper::http::{header, HeaderValue, StatusCode};
+    use gotham::hyper::Body;
     use gotham::test::TestServer;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::time::Duration;
 
     use httpmock::prelude::*;
     use mime::Mime;
-    use sentry_tunnel::config::Config;
-    use sentry_tunnel::envelope::BodyError;
-    use sentry_tunnel::server::{router, HeaderError};
+    use sentry_tunnel::breaker::BreakerConfig;
+    use sentry_tunnel::client::{parse_proxy, ClientConfig, DnsOverride};
+    use sentry_tunnel::compression::{DecodingError, UpstreamCompression};
+    use sentry_tunnel::config::{Config, Route};
+    use sentry_tunnel::envelope::{dsn_host_is_valid, BodyError, EnvelopeItem, SentryEnvelope};
+    use sentry_tunnel::pool::Balancing;
+    use sentry_tunnel::queue::{QueueConfig, QueueFullPolicy};
+    use sentry_tunnel::retry::RetryPolicy;
+    use sentry_tunnel::server::{router, HeaderError, MAX_RESPONSE_SIZE};
+    use sentry_tunnel::shedding::{envelope_priority, LoadShedder, Priority};
+    use sentry_tunnel::spool::{EvictionPolicy, Spool, SpoolConfig, SpoolError};
+    use sentry_tunnel::throttle::{BucketConfig, Limiter, MAX_TRACKED_KEYS};
 
     #[test]
     fn test_correct_behaviour() {
@@ -18,17 +34,16 @@ mod tests {
             then.status(200);
         });
         let test_config = Config {
-            remote_hosts: Config::clean_remote_hosts(&[server.url("")]),
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
             project_ids: vec!["5".to_string()],
             port: 7878,
             tunnel_path: "/tunnel".to_string(),
             ip: "0.0.0.0".to_string(),
+            ..Default::default()
         };
-        let test_server = TestServer::new(router(
-            &test_config.tunnel_path.clone(),
-            test_config.clone(),
-        ))
-        .unwrap();
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
         let json = r#"{"sent_at":"2021-10-14T17:10:40.136Z","sdk":{"name":"sentry.javascript.browser","version":"6.13.3"},"dsn":"http://public@HOST_TEST_REPLACE/5"}
         {"type":"session"}
         {"sid":"751d80dc94e34cd282a2cf1fe698a8d2","init":true,"started":"2021-10-14T17:10:40.135Z","timestamp":"2021-10-14T17:10:40.135Z","status":"ok","errors":0,"attrs":{"release":"test_project@1.0"}"#;
@@ -46,7 +61,7 @@ mod tests {
             )
             .with_header(
                 header::CONTENT_LENGTH,
-                HeaderValue::from_str(&format!("{}", json.as_bytes().len())).unwrap(),
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
             )
             .perform()
             .unwrap();
@@ -58,17 +73,16 @@ mod tests {
     #[test]
     fn test_invalid_project_id() {
         let test_config = Config {
-            remote_hosts: vec![Host("https://sentry.example.com/".to_string())],
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
             project_ids: vec!["5".to_string()],
             port: 7878,
             tunnel_path: "/tunnel".to_string(),
             ip: "0.0.0.0".to_string(),
+            ..Default::default()
         };
-        let test_server = TestServer::new(router(
-            &test_config.tunnel_path.clone(),
-            test_config.clone(),
-        ))
-        .unwrap();
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
         let json = r#"{"sent_at":"2021-10-14T17:10:40.136Z","sdk":{"name":"sentry.javascript.browser","version":"6.13.3"},"dsn":"https://public@sentry.example.com/4"}
         {"type":"session"}
         {"sid":"751d80dc94e34cd282a2cf1fe698a8d2","init":true,"started":"2021-10-14T17:10:40.135Z","timestamp":"2021-10-14T17:10:40.135Z","status":"ok","errors":0,"attrs":{"release":"test_project@1.0"}"#;
@@ -82,7 +96,7 @@ mod tests {
             )
             .with_header(
                 header::CONTENT_LENGTH,
-                HeaderValue::from_str(&format!("{}", json.as_bytes().len())).unwrap(),
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
             )
             .perform()
             .unwrap();
@@ -97,17 +111,16 @@ mod tests {
     #[test]
     fn test_missing_dsn() {
         let test_config = Config {
-            remote_hosts: vec![Host("https://sentry.example.com/".to_string())],
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
             project_ids: vec!["5".to_string()],
             port: 7878,
             tunnel_path: "/tunnel".to_string(),
             ip: "0.0.0.0".to_string(),
+            ..Default::default()
         };
-        let test_server = TestServer::new(router(
-            &test_config.tunnel_path.clone(),
-            test_config.clone(),
-        ))
-        .unwrap();
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
         let json = r#"{"sent_at":"2021-10-14T17:10:40.136Z","sdk":{"name":"sentry.javascript.browser","version":"6.13.3"}}
         {"type":"session"}
         {"sid":"751d80dc94e34cd282a2cf1fe698a8d2","init":true,"started":"2021-10-14T17:10:40.135Z","timestamp":"2021-10-14T17:10:40.135Z","status":"ok","errors":0,"attrs":{"release":"test_project@1.0"}"#;
@@ -121,7 +134,7 @@ mod tests {
             )
             .with_header(
                 header::CONTENT_LENGTH,
-                HeaderValue::from_str(&format!("{}", json.as_bytes().len())).unwrap(),
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
             )
             .perform()
             .unwrap();
@@ -133,20 +146,100 @@ mod tests {
         assert_eq!(String::from_utf8(body).unwrap(), expc);
     }
 
+    #[test]
+    fn test_missing_dsn_with_auth_header() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "clientkey");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            default_dsn: Some(
+                format!("http://public@{}/5", server.address())
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = r#"{"sent_at":"2021-10-14T17:10:40.136Z","sdk":{"name":"sentry.javascript.browser","version":"6.13.3"}}
+        {"type":"session"}
+        {"sid":"751d80dc94e34cd282a2cf1fe698a8d2","init":true,"started":"2021-10-14T17:10:40.135Z","timestamp":"2021-10-14T17:10:40.135Z","status":"ok","errors":0,"attrs":{"release":"test_project@1.0"}"#;
+        let mime = "application/json".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                json,
+                mime,
+            )
+            .with_header(
+                "X-Sentry-Auth",
+                HeaderValue::from_static("Sentry sentry_key=clientkey, sentry_version=7"),
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_missing_dsn_with_route_default_dsn() {
+        assert!(
+            "https://sentry.example.com|https://public@other.example.com/5"
+                .parse::<Route>()
+                .is_err()
+        );
+
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "routekey");
+            then.status(200);
+        });
+        let route = format!("{}|http://routekey@{}/5", server.url(""), server.address());
+        let test_config = Config {
+            routes: HashMap::from([("5".to_string(), route.parse::<Route>().unwrap())]),
+            default_dsn: Some("https://public@sentry.example.com/1".parse().unwrap()),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{}\n{\"type\":\"session\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/api/5/envelope/", json, mime)
+            .perform()
+            .unwrap();
+
+        // The default dsn of the route is used instead of the one of the config
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
     #[test]
     fn test_dsn_host_invalid() {
         let test_config = Config {
-            remote_hosts: vec![Host("https://sentry.example.com/".to_string())],
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
             project_ids: vec!["5".to_string()],
             port: 7878,
             tunnel_path: "/tunnel".to_string(),
             ip: "0.0.0.0".to_string(),
+            ..Default::default()
         };
-        let test_server = TestServer::new(router(
-            &test_config.tunnel_path.clone(),
-            test_config.clone(),
-        ))
-        .unwrap();
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
         let json = r#"{"sent_at":"2021-10-14T17:10:40.136Z","sdk":{"name":"sentry.javascript.browser","version":"6.13.3"},"dsn":"https://public@not_a_valid_host.example.com/5"}
         {"type":"session"}
         {"sid":"751d80dc94e34cd282a2cf1fe698a8d2","init":true,"started":"2021-10-14T17:10:40.135Z","timestamp":"2021-10-14T17:10:40.135Z","status":"ok","errors":0,"attrs":{"release":"test_project@1.0"}"#;
@@ -160,7 +253,7 @@ mod tests {
             )
             .with_header(
                 header::CONTENT_LENGTH,
-                HeaderValue::from_str(&format!("{}", json.as_bytes().len())).unwrap(),
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
             )
             .perform()
             .unwrap();
@@ -172,6 +265,49 @@ mod tests {
         assert_eq!(String::from_utf8(body).unwrap(), expc);
     }
     
+    #[test]
+    fn test_dsn_port_invalid() {
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[
+                "https://sentry.example.com:3000".to_string()
+            ])
+            .unwrap(),
+            project_ids: vec!["5".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json =
+            "{\"dsn\":\"https://public@sentry.example.com:9000/5\"}\n{\"type\":\"session\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                json,
+                mime,
+            )
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.read_body().unwrap();
+        let expc = format!("{}", HeaderError::InvalidHost);
+
+        assert_eq!(String::from_utf8(body).unwrap(), expc);
+    }
+
+    #[test]
+    fn test_invalid_remote_host() {
+        assert!(Config::clean_remote_hosts(&["not an url".to_string()]).is_err());
+        assert!(Config::clean_remote_hosts(&["ftp://sentry.example.com".to_string()]).is_err());
+        let hosts =
+            Config::clean_remote_hosts(&["https://sentry.example.com".to_string()]).unwrap();
+        assert_eq!(hosts[0].port, 443);
+        assert_eq!(hosts[0].to_string(), "https://sentry.example.com");
+    }
+
     #[test]
     fn test_big_envelope() {
         let server = MockServer::start();
@@ -180,17 +316,16 @@ mod tests {
             then.status(200);
         });
         let test_config = Config {
-            remote_hosts: Config::clean_remote_hosts(&[server.url("")]),
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
             project_ids: vec!["5".to_string()],
             port: 7878,
             tunnel_path: "/tunnel".to_string(),
             ip: "0.0.0.0".to_string(),
+            ..Default::default()
         };
-        let test_server = TestServer::new(router(
-            &test_config.tunnel_path.clone(),
-            test_config.clone(),
-        ))
-        .unwrap();
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
         let json = r#"{"event_id":"85ed182e014747aa917583711139a6fe","sent_at":"2021-11-04T13:25:26.636Z","sdk":{"name":"sentry.javascript.react","version":"6.13.3"},"dsn":"http://public@HOST_TEST_REPLACE/5"}
 {"type":"event","sample_rates":[{}]}
 {"exception":{"values":[{"type":"Error","value":"Super test","mechanism":{"handled":true,"type":"generic"}}]},"level":"error","event_id":"85ed182e014747aa917583711139a6fe","platform":"javascript","timestamp":1636032326.628,"environment":"prod","release":"GeoCRUD-front@1.0","sdk":{"integrations":["InboundFilters","FunctionToString","TryCatch","Breadcrumbs","GlobalHandlers","LinkedErrors","Dedupe","UserAgent","BrowserTracing"],"name":"sentry.javascript.react","version":"6.13.3","packages":[{"name":"npm:@sentry/react","version":"6.13.3"}]},"breadcrumbs":[{"timestamp":1636032321.638,"category":"fetch","data":{"method":"GET","url":"/api/crud/settings/","__span":"8d0fbc950957efa7","status_code":200},"type":"http"},{"timestamp":1636032322.128,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/styles/v1/makinacorpus/cktwqn3220g7618moe1oyxbpv?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.458,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/v4/mapbox.mapbox-streets-v8,mapbox.mapbox-terrain-v2.json?secure&access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.461,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/styles/v1/makinacorpus/cktwqn3220g7618moe1oyxbpv/aooqdcrzrpe6ncdm1ykzofhaw/sprite.json?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.463,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/styles/v1/makinacorpus/cktwqn3220g7618moe1oyxbpv/aooqdcrzrpe6ncdm1ykzofhaw/sprite.png?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.505,"category":"fetch","data":{"method":"POST","url":"https://events.mapbox.com/events/v2?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A"},"level":"error","type":"http"},{"timestamp":1636032322.506,"category":"fetch","data":{"method":"POST","url":"https://events.mapbox.com/events/v2?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A"},"level":"error","type":"http"},{"timestamp":1636032322.696,"category":"fetch","data":{"method":"GET","url":"/api/crud/layers/8/features/?ordering=&page=1&page_size=50&search=","status_code":200},"type":"http"},{"timestamp":1636032322.697,"category":"fetch","data":{"method":"GET","url":"https://geocompostelle.makina-corpus.net/api/crud/layers/8/tilejson/","status_code":200},"type":"http"},{"timestamp":1636032322.903,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Medium,Arial%20Unicode%20MS%20Regular/8192-8447.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.903,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Medium,Arial%20Unicode%20MS%20Regular/0-255.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.903,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Regular,Arial%20Unicode%20MS%20Regular/0-255.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.931,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Bold,Arial%20Unicode%20MS%20Bold/0-255.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.931,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Italic,Arial%20Unicode%20MS%20Regular/0-255.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.977,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Regular,Arial%20Unicode%20MS%20Regular/512-767.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"},{"timestamp":1636032322.978,"category":"fetch","data":{"method":"GET","url":"https://api.mapbox.com/fonts/v1/mapbox/DIN%20Pro%20Regular,Arial%20Unicode%20MS%20Regular/256-511.pbf?access_token=pk.eyJ1IjoibWFraW5hY29ycHVzIiwiYSI6ImNrMXMwNjd0MDBhNGIzZm51YTQ1djVqazMifQ.5TluOfrnGyfiExCCrJXV3A","status_code":200},"type":"http"}],"request":{"url":"https://geocompostelle.makina-corpus.net/map/monuments","headers":{"User-Agent":"Mozilla/5.0 (X11; Linux x86_64; rv:94.0) Gecko/20100101 Firefox/94.0"}}}"#;
@@ -208,7 +343,7 @@ mod tests {
             )
             .with_header(
                 header::CONTENT_LENGTH,
-                HeaderValue::from_str(&format!("{}", json.as_bytes().len())).unwrap(),
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
             )
             .perform()
             .unwrap();
@@ -217,4 +352,2042 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     
     }
+
+    #[test]
+    fn test_binary_attachment() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            ip: "0.0.0.0".to_string(),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let attachment: &[u8] = &[0xff, 0xfe, 0x00, b'\n', 0xc3, 0x28];
+        let mut body = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"attachment\",\"length\":{}}}\n",
+            server.address(),
+            attachment.len()
+        )
+        .into_bytes();
+        body.extend_from_slice(attachment);
+        let body_length = body.len();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                body,
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", body_length)).unwrap(),
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_gzip_body() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("{\"type\":\"session\"}");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            ip: "0.0.0.0".to_string(),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{\"sid\":\"751d80dc94e34cd282a2cf1fe698a8d2\"}}",
+            server.address()
+        );
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(json.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+        let body_length = body.len();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                body,
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", body_length)).unwrap(),
+            )
+            .with_header(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_large_gzip_body() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("end of the attachment");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            ip: "0.0.0.0".to_string(),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        // A payload that does not compress well, so that the ratio stays under the limit
+        let mut seed: u32 = 42;
+        let mut attachment = (0..2_000_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b'a' + (seed >> 16) as u8 % 26
+            })
+            .collect::<Vec<_>>();
+        attachment.extend_from_slice(b"end of the attachment");
+        let mut json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"attachment\",\"length\":{}}}\n",
+            server.address(),
+            attachment.len()
+        )
+        .into_bytes();
+        json.extend_from_slice(&attachment);
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&json).unwrap();
+        let body = encoder.finish().unwrap();
+        let body_length = body.len();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                body,
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", body_length)).unwrap(),
+            )
+            .with_header(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_decompression_bomb() {
+        let test_config = Config {
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
+            project_ids: vec!["5".to_string()],
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            ip: "0.0.0.0".to_string(),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        encoder.write_all(&vec![b' '; 5_000_000]).unwrap();
+        let body = encoder.finish().unwrap();
+        let body_length = body.len();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                body,
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", body_length)).unwrap(),
+            )
+            .with_header(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.read_body().unwrap();
+        let expc = format!("{}", DecodingError::CompressionRatioTooHigh);
+
+        assert_eq!(String::from_utf8(body).unwrap(), expc);
+    }
+
+    #[test]
+    fn test_compressed_forwarding() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .header("content-encoding", "zstd");
+            then.status(200);
+        });
+        let remote_hosts = Config::clean_remote_hosts(&[server.url("")]).unwrap();
+        let mut host_compression = HashMap::new();
+        host_compression.insert(remote_hosts[0].clone(), UpstreamCompression::Zstd);
+        let test_config = Config {
+            remote_hosts,
+            project_ids: vec!["5".to_string()],
+            host_compression,
+            compression_threshold: 10,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{\"sid\":\"751d80dc94e34cd282a2cf1fe698a8d2\"}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                json.clone(),
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_compressed_forwarding_with_retries() {
+        let server = MockServer::start();
+        let unavailable_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .header("content-encoding", "gzip");
+            then.status(503);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            default_compression: UpstreamCompression::Gzip,
+            retry: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        // Big enough to be compressed on the blocking thread pool
+        let attachment = "x".repeat(200_000);
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"attachment\",\"length\":{}}}\n{}",
+            server.address(),
+            attachment.len(),
+            attachment
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        unavailable_mock.assert_hits(3);
+    }
+
+    #[test]
+    fn test_streamed_body() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("{\"sid\":\"751d80dc94e34cd282a2cf1fe698a8d2\"}");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            stream_bodies: true,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{\"sid\":\"751d80dc94e34cd282a2cf1fe698a8d2\"}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                json.clone(),
+                mime,
+            )
+            .with_header(
+                header::CONTENT_LENGTH,
+                HeaderValue::from_str(&format!("{}", json.len())).unwrap(),
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_chunked_body_without_content_length() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let chunks: Vec<Result<String, std::io::Error>> = vec![
+            Ok(format!(
+                "{{\"dsn\":\"http://public@{}/5\"}}\n",
+                server.address()
+            )),
+            Ok("{\"type\":\"session\"}\n".to_string()),
+            Ok("{\"sid\":\"751d80dc94e34cd282a2cf1fe698a8d2\"}".to_string()),
+        ];
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                Body::wrap_stream(stream::iter(chunks)),
+                mime,
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_chunked_body_too_big() {
+        let test_config = Config {
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
+            project_ids: vec!["5".to_string()],
+            max_content_size: 1000,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let chunks: Vec<Result<String, std::io::Error>> = vec![
+            Ok("{\"dsn\":\"https://public@sentry.example.com/5\"}\n".to_string()),
+            Ok("{\"type\":\"attachment\",\"length\":2000}\n".to_string()),
+            Ok("x".repeat(2000)),
+        ];
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost".to_owned() + &test_config.tunnel_path,
+                Body::wrap_stream(stream::iter(chunks)),
+                mime,
+            )
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.read_body().unwrap();
+        let expc = format!("{}", HeaderError::ContentIsTooBig);
+
+        assert_eq!(String::from_utf8(body).unwrap(), expc);
+    }
+
+    #[test]
+    fn test_ingest_envelope_route() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "public");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            default_dsn: Some(
+                format!("http://default@{}/1", server.address())
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@tunnel.example.com/5\"}\n{\"type\":\"session\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/api/5/envelope/", json, mime)
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_ingest_store_route() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "clientkey")
+                .body_contains("{\"length\":65,\"type\":\"event\"}")
+                .body_contains(
+                    "{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\",\"message\":\"hello\"}",
+                );
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            default_dsn: Some(
+                format!("http://default@{}/1", server.address())
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\",\"message\":\"hello\"}";
+        let mime = "application/json".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/api/5/store/?sentry_key=clientkey",
+                json,
+                mime,
+            )
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_ingest_store_route_invalid_event() {
+        let test_config = Config {
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
+            project_ids: vec!["5".to_string()],
+            default_dsn: Some("https://default@sentry.example.com/1".parse().unwrap()),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/json".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/api/5/store/?sentry_key=clientkey",
+                "{\"message\":",
+                mime,
+            )
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8(response.read_body().unwrap()).unwrap();
+        assert!(body.starts_with("Failed to parse event json"), "{}", body);
+    }
+
+    #[test]
+    fn test_ingest_route_project_mismatch() {
+        let test_config = Config {
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
+            project_ids: vec!["5".to_string(), "6".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"session\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/api/6/envelope/", json, mime)
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.read_body().unwrap();
+        let expc = format!("{}", BodyError::ProjectIdMismatch);
+
+        assert_eq!(String::from_utf8(body).unwrap(), expc);
+    }
+
+    #[test]
+    fn test_route_target() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "public");
+            then.status(200);
+        });
+        let route = Route {
+            upstream: "https://sentry.example.com".parse().unwrap(),
+            target: Some(server.url("").parse().unwrap()),
+            default_dsn: None,
+        };
+        let test_config = Config {
+            routes: HashMap::from([("5".to_string(), route)]),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"session\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_wildcard_route() {
+        assert!("https://*.ingest.sentry.io".parse::<Route>().is_err());
+        assert!("https://*.ingest.sentry.io>http://relay.internal:3000"
+            .parse::<Route>()
+            .is_ok());
+
+        let server = MockServer::start();
+        let dsn = format!(
+            "http://public@relay.localhost:{}/5",
+            server.address().port()
+        );
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains(&dsn);
+            then.status(200);
+        });
+        let route = format!(
+            "http://*.localhost:{}>{}",
+            server.address().port(),
+            server.url("")
+        );
+        let test_config = Config {
+            routes: HashMap::from([("5".to_string(), route.parse::<Route>().unwrap())]),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!("{{\"dsn\":\"{}\"}}\n{{\"type\":\"session\"}}\n{{}}", dsn);
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/api/5/envelope/", json, mime)
+            .perform()
+            .unwrap();
+
+        // The dsn matches the wildcard upstream, so it is kept as is
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_route_upstream_mismatch() {
+        let mut routes = HashMap::new();
+        routes.insert(
+            "5".to_string(),
+            "https://sentry.example.com".parse::<Route>().unwrap(),
+        );
+        routes.insert(
+            "other".to_string(),
+            "https://other.example.com".parse::<Route>().unwrap(),
+        );
+        let test_config = Config {
+            routes,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+
+        let json = "{\"dsn\":\"https://public@other.example.com/5\"}\n{\"type\":\"session\"}\n{}";
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime.clone())
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.read_body().unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!("{}", HeaderError::InvalidHost)
+        );
+
+        let json = "{\"dsn\":\"https://public@sentry.example.com/6\"}\n{\"type\":\"session\"}\n{}";
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.read_body().unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!("{}", BodyError::InvalidProjectId)
+        );
+    }
+
+    #[test]
+    fn test_allowed_dsns() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .query_param("sentry_key", "public");
+            then.status(200);
+        });
+        let test_config = Config {
+            allowed_dsns: vec![format!("http://public@{}/5", server.address())
+                .parse()
+                .unwrap()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{}}",
+            server.address()
+        );
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime.clone())
+            .perform()
+            .unwrap();
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for dsn in [
+            format!("http://other@{}/5", server.address()),
+            format!("http://public@{}/6", server.address()),
+            "http://public@sentry.example.com/5".to_string(),
+        ] {
+            let json = format!("{{\"dsn\":\"{}\"}}\n{{\"type\":\"session\"}}\n{{}}", dsn);
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json, mime.clone())
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = response.read_body().unwrap();
+            assert_eq!(
+                String::from_utf8(body).unwrap(),
+                format!("{}", BodyError::DsnNotAllowed)
+            );
+        }
+        sentry_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_wildcard_remote_host() {
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[
+                "https://*.ingest.sentry.io".to_string(),
+                "https://o123456.ingest.*.sentry.io".to_string(),
+            ])
+            .unwrap(),
+            project_ids: vec!["5".to_string()],
+            org_ids: vec![123456, 42],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+
+        for host in ["o42.ingest.sentry.io", "o123456.ingest.de.sentry.io"] {
+            let dsn = format!("https://public@{}/5", host).parse().unwrap();
+            assert!(
+                dsn_host_is_valid(&dsn, &test_config.remote_hosts),
+                "{}",
+                host
+            );
+            assert!(test_config.org_id_is_allowed(&dsn), "{}", host);
+        }
+        for (host, error) in [
+            ("o42.ingest.de.sentry.io", HeaderError::InvalidHost),
+            (
+                "o123456.ingest.sentry.io.evil.com",
+                HeaderError::InvalidHost,
+            ),
+            ("o7.ingest.sentry.io", HeaderError::InvalidOrganization),
+        ] {
+            let json = format!(
+                "{{\"dsn\":\"https://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{}}",
+                host
+            );
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json, mime.clone())
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", host);
+            let body = response.read_body().unwrap();
+            assert_eq!(String::from_utf8(body).unwrap(), format!("{}", error));
+        }
+    }
+
+    #[test]
+    fn test_dsn_rewrite() {
+        let server = MockServer::start();
+        let new_dsn = format!("http://rotated@{}/7", server.address());
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/7/envelope/")
+                .query_param("sentry_key", "rotated")
+                .body_contains(format!("{{\"dsn\":\"{}\",\"sdk\":\"test\"}}\n", new_dsn))
+                .body_contains("{\"type\":\"session\"}\n{}");
+            then.status(200);
+        });
+        for stream_bodies in [false, true] {
+            let test_config = Config {
+                remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+                project_ids: vec!["5".to_string()],
+                dsn_rewrites: HashMap::from([(
+                    ("public".to_string(), 5),
+                    new_dsn.parse().unwrap(),
+                )]),
+                stream_bodies,
+                ..Default::default()
+            };
+            let test_server = TestServer::new(
+                router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap(),
+            )
+            .unwrap();
+            let json = format!(
+                "{{\"dsn\":\"http://public@{}/5\",\"sdk\":\"test\"}}\n{{\"type\":\"session\"}}\n{{}}",
+                server.address()
+            );
+            let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json, mime)
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        sentry_mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_upstream_response_passthrough() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(429)
+                .header("Retry-After", "60")
+                .header("X-Sentry-Rate-Limits", "60:error:organization")
+                .header("Content-Type", "application/json")
+                .body("{\"detail\":\"rate limited\"}");
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+
+        sentry_mock.assert();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let headers = response.headers();
+        assert_eq!(headers.get("Retry-After").unwrap(), "60");
+        assert_eq!(
+            headers.get("X-Sentry-Rate-Limits").unwrap(),
+            "60:error:organization"
+        );
+        assert_eq!(headers.get("Content-Type").unwrap(), "application/json");
+        let body = response.read_body().unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "{\"detail\":\"rate limited\"}"
+        );
+    }
+
+    #[test]
+    fn test_upstream_response_size_limit() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).body("x".repeat(100_000));
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.read_body().unwrap().len() as u64,
+            MAX_RESPONSE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_cached_rate_limits() {
+        let server = MockServer::start();
+        let event_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("{\"type\":\"event\"}");
+            then.status(429)
+                .header("X-Sentry-Rate-Limits", "60:error;transaction:key");
+        });
+        let session_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("{\"type\":\"session\"}");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let header = format!("{{\"dsn\":\"http://public@{}/5\"}}", server.address());
+        let event = format!("{}\n{{\"type\":\"event\"}}\n{{}}", header);
+        let event_and_session = format!(
+            "{}\n{{\"type\":\"event\"}}\n{{}}\n{{\"type\":\"session\"}}\n{{}}",
+            header
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let post = |body: &str| {
+            test_server
+                .client()
+                .post("http://localhost/tunnel", body.to_string(), mime.clone())
+                .perform()
+                .unwrap()
+        };
+
+        assert_eq!(post(&event).status(), StatusCode::TOO_MANY_REQUESTS);
+        event_mock.assert_hits(1);
+
+        let response = post(&event);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("Retry-After"));
+        let limits = response.headers().get("X-Sentry-Rate-Limits").unwrap();
+        assert!(limits.to_str().unwrap().contains(":error:key"));
+        event_mock.assert_hits(1);
+
+        assert_eq!(post(&event_and_session).status(), StatusCode::OK);
+        event_mock.assert_hits(1);
+        session_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_rate_limit_keys_are_bounded() {
+        let limiter = Limiter::new(BucketConfig {
+            rate: 0.001,
+            burst: 1.0,
+        });
+        assert!(limiter.take("10.0.0.1").is_ok());
+        assert!(limiter.take("10.0.0.1").is_err());
+        for index in 1..MAX_TRACKED_KEYS {
+            assert!(limiter.take(&index.to_string()).is_ok());
+        }
+        // The bucket was used after every other one, so it is still tracked
+        assert!(limiter.take("10.0.0.1").is_err());
+        assert_eq!(limiter.stats()["keys"], MAX_TRACKED_KEYS);
+
+        for index in MAX_TRACKED_KEYS..3 * MAX_TRACKED_KEYS {
+            assert!(limiter.take(&index.to_string()).is_ok());
+        }
+        assert_eq!(limiter.stats()["keys"], MAX_TRACKED_KEYS);
+        // The least recently used buckets were forgotten to make room for the new keys
+        assert!(limiter.take("10.0.0.1").is_ok());
+    }
+
+    #[test]
+    fn test_local_rate_limits() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            ip_rate_limit: Some("0.001:3".parse().unwrap()),
+            origin_rate_limit: Some("0.001:1".parse().unwrap()),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let post = |origin: &str| {
+            test_server
+                .client()
+                .post("http://localhost/tunnel", json.clone(), mime.clone())
+                .with_header(header::ORIGIN, HeaderValue::from_str(origin).unwrap())
+                .perform()
+                .unwrap()
+        };
+
+        assert_eq!(post("https://a.example.com").status(), StatusCode::OK);
+        let response = post("https://a.example.com");
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("Retry-After"));
+        assert!(response.headers().contains_key("X-Sentry-Rate-Limits"));
+        // The request refused by the origin limit did not take a token of the ip limit
+        assert_eq!(post("https://b.example.com").status(), StatusCode::OK);
+        assert_eq!(post("https://c.example.com").status(), StatusCode::OK);
+        assert_eq!(
+            post("https://d.example.com").status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        sentry_mock.assert_hits(3);
+
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert_eq!(stats["rate_limits"]["origin"]["burst"], 1.0);
+        assert_eq!(stats["rate_limits"]["origin"]["keys"], 4);
+        assert_eq!(stats["rate_limits"]["origin"]["empty"], 3);
+        assert_eq!(stats["rate_limits"]["ip"]["keys"], 1);
+        assert_eq!(stats["rate_limits"]["ip"]["empty"], 1);
+        // Client ips and origins are not shown
+        assert!(!stats.to_string().contains("example.com"));
+        assert!(stats["rate_limits"]["project"].is_null());
+    }
+
+    #[test]
+    fn test_upstream_retries() {
+        let server = MockServer::start();
+        let unavailable_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(503);
+        });
+        let failing_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/6/envelope/");
+            then.status(500);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string(), "6".to_string()],
+            retry: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        for (project, status) in [
+            (5, StatusCode::SERVICE_UNAVAILABLE),
+            (6, StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let json = format!(
+                "{{\"dsn\":\"http://public@{}/{}\"}}\n{{\"type\":\"session\"}}\n{{}}",
+                server.address(),
+                project
+            );
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json, mime.clone())
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+        // 503 is retried until the attempts run out, 500 is not retried
+        unavailable_mock.assert_hits(3);
+        failing_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            deadline: Duration::from_secs(10),
+            ..Default::default()
+        };
+        for (attempt, expected) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let backoff = policy.backoff(attempt);
+            assert!(
+                backoff >= Duration::from_millis(expected / 2),
+                "{:?}",
+                backoff
+            );
+            assert!(backoff <= Duration::from_millis(expected), "{:?}", backoff);
+        }
+    }
+
+    #[test]
+    fn test_streamed_body_too_big_is_not_a_relay_failure() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            stream_bodies: true,
+            max_content_size: 1000,
+            breaker: Some(BreakerConfig {
+                window: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let chunks: Vec<Result<String, std::io::Error>> = vec![
+            Ok(format!(
+                "{{\"dsn\":\"http://public@{}/5\"}}\n",
+                server.address()
+            )),
+            Ok("{\"type\":\"attachment\",\"length\":2000}\n".to_string()),
+            Ok("x".repeat(2000)),
+        ];
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                Body::wrap_stream(stream::iter(chunks)),
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert!(stats["circuit_breakers"][server.url("")].is_null());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let server = MockServer::start();
+        let mut failing_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(500);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            breaker: Some(BreakerConfig {
+                window: 2,
+                failure_ratio: 0.5,
+                open_duration: Duration::from_millis(300),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let post = || {
+            test_server
+                .client()
+                .post(
+                    "http://localhost/tunnel",
+                    json.clone(),
+                    "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+                )
+                .perform()
+                .unwrap()
+        };
+        let breaker_state = || {
+            let response = test_server
+                .client()
+                .get("http://localhost/stats")
+                .perform()
+                .unwrap();
+            let stats: serde_json::Value =
+                serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+            stats["circuit_breakers"][server.url("")]["state"].clone()
+        };
+
+        assert_eq!(post().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(breaker_state(), "closed");
+        assert_eq!(post().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(breaker_state(), "open");
+
+        // The relay is not called while the breaker is open
+        let response = post();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        failing_mock.assert_hits(2);
+
+        // Once the relay is healthy again, the probe closes the breaker
+        failing_mock.delete();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(breaker_state(), "half-open");
+        assert_eq!(post().status(), StatusCode::OK);
+        assert_eq!(breaker_state(), "closed");
+        sentry_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_relay_pool() {
+        let first_relay = MockServer::start();
+        let second_relay = MockServer::start();
+        let first_mock = first_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let second_mock = second_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&["https://sentry.example.com".to_string()])
+                .unwrap(),
+            project_ids: vec!["5".to_string()],
+            relays: Config::clean_remote_hosts(&[first_relay.url(""), second_relay.url("")])
+                .unwrap(),
+            balancing: Balancing::RoundRobin,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"event\"}\n{}";
+        for _ in 0..4 {
+            let response = test_server
+                .client()
+                .post(
+                    "http://localhost/tunnel",
+                    json,
+                    "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+                )
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        first_mock.assert_hits(2);
+        second_mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_relay_failover() {
+        let down_relay = MockServer::start();
+        let up_relay = MockServer::start();
+        let down_mock = down_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(503);
+        });
+        let up_mock = up_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&["https://sentry.example.com".to_string()])
+                .unwrap(),
+            project_ids: vec!["5".to_string()],
+            relays: Config::clean_remote_hosts(&[down_relay.url(""), up_relay.url("")]).unwrap(),
+            balancing: Balancing::LeastOutstanding,
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"event\"}\n{}";
+        for _ in 0..2 {
+            let response = test_server
+                .client()
+                .post(
+                    "http://localhost/tunnel",
+                    json,
+                    "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+                )
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Every other attempt goes to the relay that is down, and is sent again to the other one
+        down_mock.assert_hits(2);
+        up_mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_upstream_timeout() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_secs(2));
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            client: ClientConfig {
+                timeout: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let started = std::time::Instant::now();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                json,
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        sentry_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_retry_deadline_aborts_attempt() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_secs(2));
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            retry: RetryPolicy {
+                max_attempts: 3,
+                deadline: Duration::from_millis(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let started = std::time::Instant::now();
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                json,
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        sentry_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_upstream_timeout_is_not_retried() {
+        let slow_relay = MockServer::start();
+        let fast_relay = MockServer::start();
+        let slow_mock = slow_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_secs(2));
+        });
+        let fast_mock = fast_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let dir = spool_dir("timeout_not_retried");
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&["https://sentry.example.com".to_string()])
+                .unwrap(),
+            project_ids: vec!["5".to_string()],
+            relays: Config::clean_remote_hosts(&[slow_relay.url(""), fast_relay.url("")]).unwrap(),
+            balancing: Balancing::RoundRobin,
+            client: ClientConfig {
+                timeout: Duration::from_millis(200),
+                ..Default::default()
+            },
+            retry: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            spool: Some(SpoolConfig::new(dir.clone())),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"event\"}\n{}";
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                json,
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        // The relay may have stored the envelope : it is neither retried, nor sent to another
+        // relay, nor spooled
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        slow_mock.assert_hits(1);
+        fast_mock.assert_hits(0);
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert_eq!(stats["spool"]["pending"], false);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_upstream_timeout_fails_over_and_spools() {
+        let slow_relay = MockServer::start();
+        let fast_relay = MockServer::start();
+        let slow_mock = slow_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_secs(2));
+        });
+        let fast_mock = fast_relay.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let client = ClientConfig {
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&["https://sentry.example.com".to_string()])
+                .unwrap(),
+            project_ids: vec!["5".to_string()],
+            relays: Config::clean_remote_hosts(&[slow_relay.url(""), fast_relay.url("")]).unwrap(),
+            client: client.clone(),
+            retry: RetryPolicy {
+                retry_timeouts: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n{\"type\":\"event\"}\n{}";
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime.clone())
+            .perform()
+            .unwrap();
+        // The slow relay is tried first, and the envelope is sent again to the other one
+        assert_eq!(response.status(), StatusCode::OK);
+        slow_mock.assert_hits(1);
+        fast_mock.assert_hits(1);
+
+        // Without another relay, the envelope is spooled
+        let dir = spool_dir("timeout");
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[slow_relay.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            client,
+            retry: RetryPolicy {
+                retry_timeouts: true,
+                ..Default::default()
+            },
+            spool: Some(SpoolConfig::new(dir.clone())),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            slow_relay.address()
+        );
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert_eq!(stats["spool"]["pending"], true);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dns_overrides() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let relay = format!("http://relay.internal:{}", server.port());
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[relay]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            client: ClientConfig {
+                dns_overrides: vec![format!("relay.internal:{}=127.0.0.1", server.port())
+                    .parse::<DnsOverride>()
+                    .unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@relay.internal:{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.port()
+        );
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                json,
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        sentry_mock.assert_hits(1);
+
+        let dns_override = "relay.internal:9000 = 10.0.0.5"
+            .parse::<DnsOverride>()
+            .unwrap();
+        assert_eq!(dns_override.host, "relay.internal");
+        assert_eq!(dns_override.port, 9000);
+        // Without a port, a relay on another port would silently be resolved
+        assert!("relay.internal=10.0.0.5".parse::<DnsOverride>().is_err());
+        assert!("relay.internal".parse::<DnsOverride>().is_err());
+    }
+
+    #[test]
+    fn test_upstream_proxy() {
+        let proxy = MockServer::start();
+        let proxy_mock = proxy.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&["http://relay.invalid".to_string()])
+                .unwrap(),
+            project_ids: vec!["5".to_string()],
+            client: ClientConfig {
+                proxy: Some(parse_proxy(&proxy.url("")).unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = "{\"dsn\":\"http://public@relay.invalid/5\"}\n{\"type\":\"event\"}\n{}";
+        let response = test_server
+            .client()
+            .post(
+                "http://localhost/tunnel",
+                json,
+                "application/x-sentry-envelope".parse::<Mime>().unwrap(),
+            )
+            .perform()
+            .unwrap();
+        // The relay host is never resolved by the tunnel, the proxy receives the request
+        assert_eq!(response.status(), StatusCode::OK);
+        proxy_mock.assert_hits(1);
+
+        assert!(parse_proxy("socks5h://proxy.internal:1080").is_ok());
+        assert!(parse_proxy("ftp://proxy.internal").is_err());
+    }
+
+    fn spool_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sentry_tunnel_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_spool_when_relay_is_down() {
+        let server = MockServer::start();
+        let mut unavailable_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(503);
+        });
+        let dir = spool_dir("relay_down");
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            spool: Some(SpoolConfig::new(dir.clone())),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        for session in ["first", "second"] {
+            let json = format!(
+                "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{\"sid\":\"{}\"}}",
+                server.address(),
+                session
+            );
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json, mime.clone())
+                .perform()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // The first envelope was spooled after the relay failed, the second one was spooled behind it
+        assert!(unavailable_mock.hits() >= 1);
+        unavailable_mock.delete();
+
+        let first_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("first");
+            then.status(200);
+        });
+        let second_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/api/5/envelope/")
+                .body_contains("second");
+            then.status(200);
+        });
+        for _ in 0..100 {
+            if second_mock.hits() > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        first_mock.assert_hits(1);
+        second_mock.assert_hits(1);
+
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert_eq!(stats["spool"]["pending"], false);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_spool_keeps_envelopes_on_server_errors() {
+        let server = MockServer::start();
+        let mut failing_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(503);
+        });
+        let dir = spool_dir("server_errors");
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            spool: Some(SpoolConfig::new(dir.clone())),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"session\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let response = test_server
+            .client()
+            .post("http://localhost/tunnel", json, mime)
+            .perform()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        failing_mock.delete();
+
+        // A 500 is not a definitive refusal, the envelope stays in the spool
+        let mut error_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(500);
+        });
+        for _ in 0..100 {
+            if error_mock.hits() > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(error_mock.hits() > 0);
+        error_mock.delete();
+
+        let accepting_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200);
+        });
+        for _ in 0..100 {
+            if accepting_mock.hits() > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        accepting_mock.assert_hits(1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_router_fails_when_spool_can_not_be_opened() {
+        let file = spool_dir("not_a_directory");
+        std::fs::write(&file, "").unwrap();
+        let test_config = Config {
+            remote_hosts: vec!["https://sentry.example.com".parse().unwrap()],
+            project_ids: vec!["5".to_string()],
+            spool: Some(SpoolConfig::new(file.join("spool"))),
+            ..Default::default()
+        };
+        match router(&test_config.tunnel_path.clone(), test_config) {
+            Err(e) => assert!(e.starts_with("Failed to open the spool")),
+            Ok(_) => panic!("The router was built without a spool"),
+        }
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn test_spool_segments() {
+        let dir = spool_dir("segments");
+        let config = SpoolConfig {
+            max_size: 300,
+            segment_size: 100,
+            eviction: EvictionPolicy::DropNewest,
+            ..SpoolConfig::new(dir.clone())
+        };
+        let dsn = "https://public@sentry.example.com/5";
+        let spool = Spool::open(config.clone()).unwrap();
+        assert!(spool.is_empty());
+        let mut pushed = 0;
+        loop {
+            match spool.push(dsn, format!("envelope {}", pushed).as_bytes()) {
+                Ok(()) => pushed += 1,
+                Err(SpoolError::SpoolIsFull) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(pushed > 1);
+        let first = spool.peek().unwrap().unwrap();
+        assert_eq!(first.body, "envelope 0");
+        spool.ack(&first);
+        drop(spool);
+
+        // The delivered envelope is not read again after a restart
+        let spool = Spool::open(config.clone()).unwrap();
+        for expected in 1..pushed {
+            let envelope = spool.peek().unwrap().unwrap();
+            assert_eq!(envelope.body, format!("envelope {}", expected));
+            assert_eq!(envelope.dsn.project_id().value(), 5);
+            spool.ack(&envelope);
+        }
+        assert!(spool.peek().unwrap().is_none());
+        assert!(spool.is_empty());
+        drop(spool);
+
+        // When full, the oldest segments make room for new envelopes
+        let config = SpoolConfig {
+            eviction: EvictionPolicy::DropOldest,
+            ..config
+        };
+        let spool = Spool::open(config).unwrap();
+        for i in 0..20 {
+            spool
+                .push(dsn, format!("envelope {}", i).as_bytes())
+                .unwrap();
+        }
+        let oldest = spool.peek().unwrap().unwrap();
+        assert_ne!(oldest.body, "envelope 0");
+        assert_eq!(
+            spool.stats()["evicted_segments"].as_u64().map(|n| n > 0),
+            Some(true)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_async_forwarding() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_millis(500));
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            queue: Some(QueueConfig {
+                capacity: 1,
+                workers: 1,
+                when_full: QueueFullPolicy::Reject,
+            }),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\",\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let mut accepted = 0;
+        let mut rejected = 0;
+        for _ in 0..4 {
+            let response = test_server
+                .client()
+                .post("http://localhost/tunnel", json.clone(), mime.clone())
+                .perform()
+                .unwrap();
+            match response.status() {
+                StatusCode::OK => {
+                    accepted += 1;
+                    let body = response.read_body().unwrap();
+                    assert_eq!(
+                        String::from_utf8(body).unwrap(),
+                        "{\"id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}"
+                    );
+                }
+                StatusCode::SERVICE_UNAVAILABLE => rejected += 1,
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        // The relay takes 500ms to answer : at most one envelope is being forwarded and one is
+        // waiting in the queue
+        assert!((1..=2).contains(&accepted), "{} accepted", accepted);
+
+        let response = test_server
+            .client()
+            .get("http://localhost/stats")
+            .perform()
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+        assert_eq!(stats["queue"]["rejected"], rejected);
+        assert_eq!(stats["queue"]["queued"], accepted);
+
+        for _ in 0..50 {
+            if sentry_mock.hits() == accepted {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        sentry_mock.assert_hits(accepted);
+    }
+
+    #[test]
+    fn test_priorities() {
+        assert_eq!(Priority::Low.limit(2), 1);
+        assert_eq!(Priority::Normal.limit(10), 8);
+        assert_eq!(Priority::High.limit(10), 10);
+        assert_eq!(Priority::Low.limit(1), 0);
+        assert_eq!(Priority::Normal.limit(1), 1);
+        assert_eq!(Priority::High.limit(1), 1);
+        assert_eq!(Priority::Normal.limit(0), 0);
+        let shedder = LoadShedder::new(2);
+        let first = shedder.try_acquire(Priority::Low);
+        assert!(first.is_some());
+        assert!(shedder.try_acquire(Priority::Low).is_none());
+        assert!(shedder.try_acquire(Priority::High).is_some());
+        drop(first);
+        assert!(shedder.try_acquire(Priority::Low).is_some());
+    }
+
+    #[test]
+    fn test_queued_envelopes_stay_in_flight() {
+        let server = MockServer::start();
+        let sentry_mock = server.mock(|when, then| {
+            when.method(POST).path("/api/5/envelope/");
+            then.status(200).delay(Duration::from_millis(500));
+        });
+        let test_config = Config {
+            remote_hosts: Config::clean_remote_hosts(&[server.url("")]).unwrap(),
+            project_ids: vec!["5".to_string()],
+            queue: Some(QueueConfig {
+                capacity: 10,
+                workers: 1,
+                when_full: QueueFullPolicy::Reject,
+            }),
+            max_in_flight: Some(1),
+            ..Default::default()
+        };
+        let test_server =
+            TestServer::new(router(&test_config.tunnel_path.clone(), test_config.clone()).unwrap())
+                .unwrap();
+        let json = format!(
+            "{{\"dsn\":\"http://public@{}/5\"}}\n{{\"type\":\"event\"}}\n{{}}",
+            server.address()
+        );
+        let mime = "application/x-sentry-envelope".parse::<Mime>().unwrap();
+        let post = || {
+            test_server
+                .client()
+                .post("http://localhost/tunnel", json.clone(), mime.clone())
+                .perform()
+                .unwrap()
+        };
+        assert_eq!(post().status(), StatusCode::OK);
+        // The client has been answered, but the envelope is still being forwarded
+        assert_eq!(post().status(), StatusCode::TOO_MANY_REQUESTS);
+        let in_flight = || {
+            let response = test_server
+                .client()
+                .get("http://localhost/stats")
+                .perform()
+                .unwrap();
+            let stats: serde_json::Value =
+                serde_json::from_slice(&response.read_body().unwrap()).unwrap();
+            stats["load"]["in_flight"].as_u64().unwrap()
+        };
+        assert_eq!(in_flight(), 1);
+        for _ in 0..100 {
+            if in_flight() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        sentry_mock.assert_hits(1);
+        assert_eq!(post().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_envelope_priority() {
+        let envelope = |item_types: &[&str]| {
+            let mut body = "{\"dsn\":\"https://public@sentry.example.com/5\"}".to_string();
+            for item_type in item_types {
+                body.push_str(&format!("\n{{\"type\":\"{}\"}}\n{{}}", item_type));
+            }
+            SentryEnvelope::try_new_from_body(Bytes::from(body)).unwrap()
+        };
+        let defaults = HashMap::new();
+        assert_eq!(
+            envelope_priority(&envelope(&["replay_event"]), &defaults),
+            Priority::Low
+        );
+        assert_eq!(
+            envelope_priority(&envelope(&["transaction"]), &defaults),
+            Priority::Normal
+        );
+        assert_eq!(
+            envelope_priority(&envelope(&["replay_event", "event"]), &defaults),
+            Priority::High
+        );
+        assert_eq!(
+            envelope_priority(&envelope(&[]), &defaults),
+            Priority::Normal
+        );
+        assert_eq!(
+            envelope_priority(&envelope(&["not_a_sentry_item"]), &defaults),
+            Priority::Low
+        );
+
+        let priorities = HashMap::from([("transaction".to_string(), Priority::Low)]);
+        assert_eq!(
+            envelope_priority(&envelope(&["transaction"]), &priorities),
+            Priority::Low
+        );
+    }
+
+    #[test]
+    fn test_multi_item_envelope() {
+        let body = "{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\",\"dsn\":\"https://public@sentry.example.com/5\"}\n\
+            {\"type\":\"attachment\",\"length\":10,\"filename\":\"hello.txt\"}\n\
+            hello\nfoo\n\n\
+            {\"type\":\"event\"}\n\
+            {\"message\":\"hello world\",\"level\":\"error\"}\n\
+            {\"type\":\"session\",\"length\":2}\n\
+            {}";
+        let envelope = SentryEnvelope::try_new_from_body(Bytes::from(body)).unwrap();
+
+        assert_eq!(envelope.dsn.project_id().value(), 5);
+        assert_eq!(envelope.items.len(), 3);
+        assert_eq!(envelope.items[0].header()["type"], "attachment");
+        assert_eq!(envelope.items[0].payload(), "hello\nfoo\n");
+        assert_eq!(envelope.items[1].header()["type"], "event");
+        assert_eq!(
+            envelope.items[1].payload(),
+            "{\"message\":\"hello world\",\"level\":\"error\"}"
+        );
+        assert_eq!(envelope.items[2].payload(), "{}");
+    }
+
+    #[test]
+    fn test_typed_items() {
+        let body = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n\
+            {\"type\":\"transaction\"}\n\
+            {\"transaction\":\"/index\"}\n\
+            {\"type\":\"replay_recording\",\"length\":2}\n\
+            {}\n\
+            {\"type\":\"some_future_item\"}\n\
+            {\"unknown\":true}\n\
+            {\"type\":\"user_report\"}\n\
+            {\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n";
+        let envelope = SentryEnvelope::try_new_from_body(Bytes::from(body)).unwrap();
+
+        assert!(matches!(envelope.items[0], EnvelopeItem::Transaction(_)));
+        assert_eq!(envelope.items[0].json().unwrap()["transaction"], "/index");
+        assert!(matches!(
+            envelope.items[1],
+            EnvelopeItem::ReplayRecording(_)
+        ));
+        assert!(envelope.items[1].json().is_none());
+        assert!(matches!(envelope.items[2], EnvelopeItem::Unknown(_)));
+        assert_eq!(envelope.items[2].item_type(), "some_future_item");
+        assert_eq!(envelope.items[2].payload(), "{\"unknown\":true}");
+        assert!(matches!(envelope.items[3], EnvelopeItem::UserReport(_)));
+        assert_eq!(envelope.items[3].data_category(), "user_report");
+    }
+
+    #[test]
+    fn test_truncated_item_payload() {
+        let body = "{\"dsn\":\"https://public@sentry.example.com/5\"}\n\
+            {\"type\":\"attachment\",\"length\":100}\n\
+            too short";
+        let error = SentryEnvelope::try_new_from_body(Bytes::from(body)).unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<BodyError>(),
+            Some(BodyError::UnexpectedEndOfBody)
+        ));
+    }
 }